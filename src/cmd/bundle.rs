@@ -0,0 +1,123 @@
+use std::path::PathBuf;
+
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use humansize::{format_size, DECIMAL};
+use ostree::gio::Cancellable;
+
+use crate::{
+    cmd::output::{self, Format},
+    engine::{Engine, Initiator, Target},
+    Error,
+};
+
+pub fn cmd() -> Command {
+    Command::new("bundle")
+        .about("Manage update bundles")
+        .long_about(
+            "Write updates to a single file and deploy them on machines without network access",
+        )
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("create")
+                .about("Create bundle")
+                .arg(file_arg())
+                .arg(
+                    Arg::new("channel")
+                        .short('c')
+                        .long("channel")
+                        .help("Bundle the core of another channel")
+                        .action(ArgAction::Set)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("base")
+                        .long("base")
+                        .help("Store commits as deltas from the deployment with this revision")
+                        .action(ArgAction::Set)
+                        .value_parser(value_parser!(String)),
+                ),
+        )
+        .subcommand(
+            Command::new("apply")
+                .about("Import and deploy bundle")
+                .arg(file_arg()),
+        )
+}
+
+fn file_arg() -> Arg {
+    Arg::new("file")
+        .help("Bundle file")
+        .required(true)
+        .action(ArgAction::Set)
+        .value_parser(value_parser!(PathBuf))
+}
+
+pub async fn run(
+    args: &ArgMatches,
+    engine: &Engine,
+    cancellable: &Cancellable,
+) -> Result<(), Error> {
+    let cancellable = Some(cancellable);
+    let format = Format::from_args(args);
+
+    match args.subcommand() {
+        Some(("create", args)) => {
+            let file = args.get_one::<PathBuf>("file").unwrap();
+            let base = args
+                .get_one::<String>("base")
+                .map(|revision| Target::Revision(revision.clone()));
+            let remote = args.get_one::<String>("remote").map(|s| s.as_str());
+            let progress = crate::progress::get();
+            let progress = match format {
+                Format::Text => Some(&progress),
+                Format::Json => None,
+            };
+
+            let mut state = engine.state()?;
+            if let Some(channel) = args.get_one::<String>("channel") {
+                state.switch_channel(&engine.config, channel);
+            }
+
+            engine.lock()?;
+            let result = engine.record("bundle-create", Initiator::cli(), || {
+                engine.create_bundle(&state, file, base.as_ref(), remote, progress, cancellable)
+            });
+            engine.unlock();
+            let manifest = result?;
+
+            if format == Format::Json {
+                return output::print_json("bundle", &manifest);
+            }
+            let size: u64 = manifest
+                .entries
+                .iter()
+                .map(|entry| entry.metadata_size + entry.delta_size)
+                .sum();
+            println!("Wrote {} ({})", file.display(), format_size(size, DECIMAL));
+            for entry in &manifest.entries {
+                match &entry.from {
+                    Some(from) => println!("    {} {} -> {}", entry.refspec, from, entry.revision),
+                    None => println!("    {} {}", entry.refspec, entry.revision),
+                }
+            }
+        }
+        Some(("apply", args)) => {
+            let file = args.get_one::<PathBuf>("file").unwrap();
+
+            engine.lock()?;
+            let result = engine.record("bundle", Initiator::cli(), || {
+                engine.apply_bundle(file, cancellable)
+            });
+            engine.unlock();
+
+            if result? {
+                println!("Bundle deployed, reboot to apply");
+            } else {
+                println!("Bundle is already deployed");
+            }
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use clap::{ArgMatches, Command};
+use tracing::info;
+use zbus::ConnectionBuilder;
+
+use crate::{
+    engine::Engine,
+    server::{schedule, Server, BUS_NAME, OBJECT_PATH},
+    Error,
+};
+
+pub fn cmd() -> Command {
+    Command::new("daemon")
+        .about("Run system updates daemon")
+        .long_about("Serve dev.rlxos.updates interface on the system bus")
+}
+
+pub async fn run(_: &ArgMatches, engine: Engine) -> Result<(), Error> {
+    tracing_subscriber::fmt::init();
+
+    let config = engine.config.clone();
+    let connection = ConnectionBuilder::system()?
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, Server::new(engine))?
+        .build()
+        .await?;
+
+    info!("serving {} at {}", BUS_NAME, OBJECT_PATH);
+    tokio::spawn(schedule(connection.clone(), config));
+    std::future::pending::<()>().await;
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use clap::{ArgMatches, Command};
+use humansize::{format_size, DECIMAL};
+use ostree::{gio::Cancellable, AsyncProgress};
+
+use crate::{
+    cmd::output::{self, Format},
+    engine::{truncate, Diff, Engine, Role},
+    Error,
+};
+
+pub fn cmd() -> Command {
+    Command::new("diff")
+        .about("Show changes in pending update")
+        .long_about(
+            "Fetch the available update without deploying it and list the files it changes \
+             against the deployment that boots next",
+        )
+}
+
+pub async fn run(
+    args: &ArgMatches,
+    engine: &Engine,
+    cancellable: &Cancellable,
+) -> Result<(), Error> {
+    let format = Format::from_args(args);
+    let progress = match format {
+        Format::Text => crate::progress::get(),
+        Format::Json => AsyncProgress::new(),
+    };
+
+    // An update replaces the pending deployment, so the next boot changes
+    // from that one when there is one.
+    let (role, base) = match engine.pending_state()? {
+        Some(pending) => (Role::Pending, pending),
+        None => (Role::Booted, engine.booted_state()?),
+    };
+    let state = engine.state()?;
+    let remote = args.get_one::<String>("remote").map(|s| s.as_str());
+
+    engine.lock()?;
+    let result = engine
+        .fetch(&state, remote, Some(&progress), Some(cancellable))
+        .and_then(|(update, _, transferred)| {
+            let mut diff = engine.diff(&base, &update, Some(cancellable))?;
+            diff.base = role.as_str().to_string();
+            diff.base_revision = base.revision.clone();
+            diff.download_size = transferred;
+            Ok(diff)
+        });
+    engine.unlock();
+    let diff = result?;
+
+    match format {
+        Format::Json => output::print_json("diff", &diff),
+        Format::Text => {
+            print(&diff);
+            Ok(())
+        }
+    }
+}
+
+fn print(diff: &Diff) {
+    println!(
+        "changes against {} deployment {}",
+        diff.base,
+        truncate(&diff.base_revision, 6)
+    );
+    if diff.is_empty() {
+        println!("no changes");
+    }
+
+    for (directory, changes) in diff.directories.iter() {
+        println!("{}:", directory);
+        for path in changes.added.iter() {
+            println!("    A {}", path);
+        }
+        for path in changes.removed.iter() {
+            println!("    D {}", path);
+        }
+        for path in changes.modified.iter() {
+            println!("    M {}", path);
+        }
+    }
+
+    let delta = format_size(diff.disk_size_delta.unsigned_abs(), DECIMAL);
+    let sign = match diff.disk_size_delta < 0 {
+        true => "-",
+        false => "+",
+    };
+    println!();
+    println!("download  : {}", format_size(diff.download_size, DECIMAL));
+    println!("disk size : {sign}{delta}");
+}
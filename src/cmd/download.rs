@@ -0,0 +1,73 @@
+use clap::{ArgMatches, Command};
+use humansize::{format_size, DECIMAL};
+use ostree::gio::Cancellable;
+use ostree::AsyncProgress;
+use serde::Serialize;
+
+use crate::{
+    cmd::output::{self, Format},
+    engine::{ChangelogEntry, Engine, Initiator},
+    Error,
+};
+
+#[derive(Serialize)]
+struct DownloadReport<'a> {
+    available: bool,
+    refs: &'a [ChangelogEntry],
+    transferred: u64,
+}
+
+pub fn cmd() -> Command {
+    Command::new("download")
+        .about("Download updates")
+        .long_about(
+            "Pull available updates without deploying them, a later update applies them offline",
+        )
+}
+
+pub async fn run(
+    args: &ArgMatches,
+    engine: &Engine,
+    cancellable: &Cancellable,
+) -> Result<(), Error> {
+    let cancellable = Some(cancellable);
+    let format = Format::from_args(args);
+    let progress = match format {
+        Format::Text => crate::progress::get(),
+        Format::Json => AsyncProgress::new(),
+    };
+    let remote = args.get_one::<String>("remote").map(|s| s.as_str());
+
+    engine.lock()?;
+    let result = engine.record("download", Initiator::cli(), || {
+        let state = engine.state()?;
+        engine.download(&state, remote, Some(&progress), cancellable)
+    });
+    engine.unlock();
+    let (available, changelog, transferred) = result?;
+
+    match format {
+        Format::Json => output::print_json(
+            "download",
+            &DownloadReport {
+                available,
+                refs: &changelog.entries,
+                transferred,
+            },
+        )?,
+        Format::Text if available => {
+            println!("{}", changelog);
+            println!(
+                "Downloaded {}, run update to apply",
+                format_size(transferred, DECIMAL)
+            );
+        }
+        Format::Text => {}
+    }
+
+    // Like update, nothing to download fails in both formats.
+    if !available {
+        return Err(Error::NoUpdateAvailable);
+    }
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use ostree::glib::DateTime;
+
+use crate::{
+    cmd::output::{self, Format},
+    engine::{Engine, Entry, State},
+    Error,
+};
+
+pub fn cmd() -> Command {
+    Command::new("history")
+        .about("Show updater history")
+        .long_about("Print the operations recorded in the transaction journal")
+        .arg(
+            Arg::new("merged")
+                .long("merged")
+                .help("Print every composition deployed, including ones pruned from the repository")
+                .action(ArgAction::SetTrue),
+        )
+}
+
+pub async fn run(args: &ArgMatches, engine: &Engine) -> Result<(), Error> {
+    if args.get_flag("merged") {
+        return merged(args, engine);
+    }
+
+    let format = Format::from_args(args);
+    let entries = engine.journal()?;
+    if format == Format::Json {
+        return output::print_json("history", &entries);
+    }
+    if entries.is_empty() {
+        println!("No recorded operations");
+        return Ok(());
+    }
+
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print_entry(entry);
+    }
+
+    Ok(())
+}
+
+fn merged(args: &ArgMatches, engine: &Engine) -> Result<(), Error> {
+    let format = Format::from_args(args);
+    let commits = engine.history()?;
+    if format == Format::Json {
+        return output::print_json("history", &commits);
+    }
+    if commits.is_empty() {
+        println!("No merged compositions");
+        return Ok(());
+    }
+
+    for (i, composition) in commits.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        let commit = &composition.commit;
+        println!("{} {}", commit.revision, date(commit.timestamp));
+        if let Some(composition) = &composition.composition {
+            println!("    composition: {}", composition);
+        }
+        println!("    {}", commit.subject);
+        for line in commit.body.lines() {
+            println!("    {}", line);
+        }
+    }
+
+    Ok(())
+}
+
+fn print_entry(entry: &Entry) {
+    let result = match entry.success {
+        true => "ok",
+        false => "failed",
+    };
+    println!(
+        "{} {} by {} (uid {}): {}",
+        date(entry.timestamp),
+        entry.operation,
+        entry.initiator.name(),
+        entry.initiator.uid(),
+        result
+    );
+
+    let revision = |state: &Option<State>| match state {
+        Some(state) => state.revision.clone(),
+        None => "-".to_string(),
+    };
+    let (old, new) = (revision(&entry.old), revision(&entry.new));
+    if old == new {
+        println!("    rev: {}", old);
+    } else {
+        println!("    rev: {} -> {}", old, new);
+    }
+    for error in &entry.errors {
+        println!("    {}", error);
+    }
+}
+
+fn date(timestamp: u64) -> String {
+    DateTime::from_unix_local(timestamp as i64)
+        .and_then(|date| date.format("%Y-%m-%d %H:%M:%S"))
+        .map(|date| date.to_string())
+        .unwrap_or_default()
+}
@@ -1,18 +1,25 @@
-use clap::{Arg, ArgAction, ArgMatches, Command, value_parser};
+use clap::{Arg, ArgAction, ArgMatches, Command};
 
-use ostree::{gio::Cancellable, AsyncProgress};
-use crate::{engine::Engine, Error};
+use humansize::{format_size, DECIMAL};
+use ostree::gio::Cancellable;
+use serde::Serialize;
+use crate::{
+    cmd::output::{self, Format},
+    engine::{Engine, Extension},
+    Error,
+};
+
+#[derive(Serialize)]
+struct ExtensionStatus<'a> {
+    #[serde(flatten)]
+    extension: &'a Extension,
+    installed: bool,
+}
 
 pub fn cmd() -> Command {
     Command::new("list")
         .about("List available extensions")
         .long_about("Print available extensions from remote server")
-        .arg(Arg::new("remote")
-            .short('r')
-            .long("remove")
-            .help("Specify remote server name or url")
-            .action(ArgAction::Set)
-            .value_parser(value_parser!(String)))
         .arg(Arg::new("all")
             .short('a')
             .long("all")
@@ -20,19 +27,56 @@ pub fn cmd() -> Command {
             .action(ArgAction::SetTrue))
 }
 
-pub async fn run(args: &ArgMatches, engine: &Engine) -> Result<(), Error> {
-    let cancellable = Cancellable::NONE;
-    let progress = AsyncProgress::new();
-    progress.connect_changed(crate::progress::update_callback);
+pub async fn run(args: &ArgMatches, engine: &Engine, cancellable: &Cancellable) -> Result<(), Error> {
+    let remote = args.get_one::<String>("remote").map(|s| s.as_str());
+    let format = Format::from_args(args);
 
-    let refs = engine.list(args.get_one::<String>("remote"), cancellable)?;
-    if refs.is_empty() {
-        println!("no extensions found");
-    } else {
+    if args.get_flag("all") {
+        let refs = engine.list(remote, Some(cancellable))?;
+        if format == Format::Json {
+            return output::print_json("refs", &refs);
+        }
         for (i, r) in refs.iter().enumerate() {
-            if r.starts_with("x86_64/extension/") || args.get_flag("all") {
-                println!("{}. {}", i + 1, r);
-            }
+            println!("{}. {}", i + 1, r);
+        }
+        return Ok(());
+    }
+
+    let installed = match engine.booted_state() {
+        Ok(state) => state
+            .extensions
+            .iter()
+            .filter_map(|extension| engine.config.extension_id(&extension.refspec))
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    let extensions = engine.extensions(remote, Some(cancellable))?;
+    let extensions = extensions
+        .iter()
+        .map(|extension| ExtensionStatus {
+            extension,
+            installed: installed.contains(&extension.id),
+        })
+        .collect::<Vec<_>>();
+
+    if format == Format::Json {
+        return output::print_json("extensions", &extensions);
+    }
+
+    if extensions.is_empty() {
+        println!("no extensions found");
+    }
+    for status in extensions.iter() {
+        let extension = status.extension;
+        println!(
+            "{} {} ({}, {})",
+            if status.installed { "*" } else { " " },
+            extension.id,
+            extension.channel,
+            format_size(extension.installed_size, DECIMAL)
+        );
+        if !extension.description.is_empty() {
+            println!("    {}", extension.description);
         }
     }
 
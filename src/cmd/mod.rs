@@ -1,11 +1,25 @@
 use std::path::PathBuf;
 
 use clap::{value_parser, Arg, ArgAction, Command};
+use ostree::gio::Cancellable;
+use ostree::prelude::*;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, Level};
 use crate::{engine::Engine, Error};
 
+mod bundle;
+mod daemon;
+mod diff;
+mod download;
+mod history;
 mod list;
+mod output;
+mod pin;
+mod remote;
+mod rollback;
 mod status;
 mod unlock;
+mod unstage;
 mod update;
 
 pub async fn run() -> Result<(), Error> {
@@ -25,6 +39,15 @@ pub async fn run() -> Result<(), Error> {
                 .default_value("/")
                 .value_parser(value_parser!(PathBuf)),
         )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .global(true)
+                .help("Updater configuration file")
+                .action(ArgAction::Set)
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
         .arg(
             Arg::new("remote")
                 .long("remote")
@@ -34,11 +57,31 @@ pub async fn run() -> Result<(), Error> {
                 .required(false)
                 .value_parser(value_parser!(String)),
         )
+        .arg(
+            Arg::new("deltas")
+                .long("deltas")
+                .help("Static delta use for pulls: auto, force, disable or fallback")
+                .action(ArgAction::Set)
+                .global(true)
+                .required(false)
+                .value_parser(["auto", "force", "disable", "fallback"]),
+        )
+        .arg(output::arg())
         .arg_required_else_help(true)
         .subcommand(update::cmd())
         .subcommand(status::cmd())
         .subcommand(unlock::cmd())
         .subcommand(list::cmd())
+        .subcommand(diff::cmd())
+        .subcommand(download::cmd())
+        .subcommand(history::cmd())
+        .subcommand(rollback::cmd())
+        .subcommand(pin::cmd())
+        .subcommand(pin::unpin_cmd())
+        .subcommand(unstage::cmd())
+        .subcommand(remote::cmd())
+        .subcommand(bundle::cmd())
+        .subcommand(daemon::cmd())
         .get_matches();
 
     if matches.get_flag("version") {
@@ -58,14 +101,72 @@ pub async fn run() -> Result<(), Error> {
     };
 
 
-    let engine = Engine::new(matches.get_one::<PathBuf>("sysroot").unwrap())?;
+    let mut engine = Engine::new(
+        matches.get_one::<PathBuf>("sysroot").unwrap(),
+        matches.get_one::<PathBuf>("config"),
+    )?;
+    if let Some(deltas) = matches.get_one::<String>("deltas") {
+        engine.config.deltas = deltas.parse()?;
+    }
 
+    // The daemon stops on SIGTERM and cancels jobs through D-Bus instead,
+    // and logs on its own. Other commands show warnings such as merge
+    // conflicts on stderr.
+    let cancellable = Cancellable::new();
+    if matches.subcommand_name() != Some("daemon") {
+        cancel_on_signal(&cancellable)?;
+        tracing_subscriber::fmt()
+            .with_max_level(Level::WARN)
+            .with_writer(std::io::stderr)
+            .without_time()
+            .with_target(false)
+            .init();
+    }
 
     match matches.subcommand() {
-        Some(("update", args)) => update::run(args, &engine).await,
+        Some(("update", args)) => update::run(args, &engine, &cancellable).await,
         Some(("status", args)) => status::run(args, &engine).await,
         Some(("unlock", args)) => unlock::run(args, &engine).await,
-        Some(("list", args)) => list::run(args, &engine).await,
+        Some(("list", args)) => list::run(args, &engine, &cancellable).await,
+        Some(("diff", args)) => diff::run(args, &engine, &cancellable).await,
+        Some(("download", args)) => download::run(args, &engine, &cancellable).await,
+        Some(("history", args)) => history::run(args, &engine).await,
+        Some(("rollback", args)) => rollback::run(args, &engine, &cancellable).await,
+        Some(("pin", args)) => pin::run(args, &engine, true).await,
+        Some(("unpin", args)) => pin::run(args, &engine, false).await,
+        Some(("unstage", args)) => unstage::run(args, &engine, &cancellable).await,
+        Some(("remote", args)) => remote::run(args, &engine, &cancellable).await,
+        Some(("bundle", args)) => bundle::run(args, &engine, &cancellable).await,
+        Some(("daemon", args)) => daemon::run(args, engine).await,
         _ => unreachable!(),
     }
 }
+
+/// Trigger `cancellable` on SIGINT or SIGTERM so running operations stop
+/// at the next safe point instead of killing the process mid-transaction.
+/// Later signals don't exit either: the command returns through the
+/// cancellation, so temporary remotes, scratch files and the sysroot lock
+/// are cleaned up.
+fn cancel_on_signal(cancellable: &Cancellable) -> Result<(), Error> {
+    let cancellable = cancellable.clone();
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = interrupt.recv() => {},
+                _ = terminate.recv() => {},
+            }
+            if cancellable.is_cancelled() {
+                eprintln!("Still cancelling, waiting for the current operation to stop");
+                continue;
+            }
+            info!("Cancelling current operation");
+            eprintln!("Cancelling current operation");
+            cancellable.cancel();
+        }
+    });
+
+    Ok(())
+}
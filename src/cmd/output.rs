@@ -0,0 +1,45 @@
+use clap::{Arg, ArgAction, ArgMatches};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::Error;
+
+/// Version of the JSON documents printed with `--output json`. Bump it on
+/// any incompatible change to a released layout.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn from_args(args: &ArgMatches) -> Format {
+        match args.get_one::<String>("output").map(|s| s.as_str()) {
+            Some("json") => Format::Json,
+            _ => Format::Text,
+        }
+    }
+}
+
+pub fn arg() -> Arg {
+    Arg::new("output")
+        .short('o')
+        .long("output")
+        .global(true)
+        .help("Output format")
+        .action(ArgAction::Set)
+        .value_parser(["text", "json"])
+        .default_value("text")
+}
+
+/// Print `value` under `key` in a versioned JSON document.
+pub fn print_json<T: Serialize>(key: &str, value: &T) -> Result<(), Error> {
+    let mut document = Map::new();
+    document.insert("version".into(), Value::from(SCHEMA_VERSION));
+    document.insert(key.into(), serde_json::to_value(value)?);
+
+    println!("{}", serde_json::to_string_pretty(&document)?);
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+
+use crate::{
+    engine::{Engine, Target},
+    Error,
+};
+
+pub fn cmd() -> Command {
+    Command::new("pin")
+        .about("Pin deployment")
+        .long_about("Keep deployment from being pruned after updates")
+        .arg(index_arg())
+}
+
+pub fn unpin_cmd() -> Command {
+    Command::new("unpin")
+        .about("Unpin deployment")
+        .long_about("Allow deployment to be pruned after updates")
+        .arg(index_arg())
+}
+
+fn index_arg() -> Arg {
+    Arg::new("index")
+        .help("Deployment index as shown by status")
+        .required(true)
+        .action(ArgAction::Set)
+        .value_parser(value_parser!(usize))
+}
+
+pub async fn run(args: &ArgMatches, engine: &Engine, pinned: bool) -> Result<(), Error> {
+    let index = *args.get_one::<usize>("index").unwrap();
+
+    engine.lock()?;
+    let result = engine.pin(&Target::Index(index), pinned);
+    engine.unlock();
+
+    match (result?, pinned) {
+        (true, true) => println!("Deployment {index} pinned"),
+        (true, false) => println!("Deployment {index} unpinned"),
+        (false, true) => println!("Deployment {index} is already pinned"),
+        (false, false) => println!("Deployment {index} is not pinned"),
+    }
+
+    Ok(())
+}
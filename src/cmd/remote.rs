@@ -0,0 +1,207 @@
+use std::path::PathBuf;
+
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use ostree::gio::Cancellable;
+
+use crate::{
+    cmd::output::{self, Format},
+    engine::{Engine, Remote, RemoteChanges, RemoteOptions},
+    Error,
+};
+
+pub fn cmd() -> Command {
+    Command::new("remote")
+        .about("Manage remotes")
+        .long_about("List, inspect and modify the remotes of the system repository")
+        .subcommand_required(true)
+        .subcommand(Command::new("list").about("List remotes"))
+        .subcommand(
+            Command::new("add")
+                .about("Add remote")
+                .arg(name_arg())
+                .arg(url_arg())
+                .arg(
+                    Arg::new("no-gpg-verify")
+                        .long("no-gpg-verify")
+                        .help("Disable GPG verification of commits")
+                        .action(ArgAction::SetTrue),
+                )
+                .args(option_args()),
+        )
+        .subcommand(
+            Command::new("modify")
+                .about("Change remote options")
+                .arg(name_arg())
+                .arg(
+                    Arg::new("gpg-verify")
+                        .long("gpg-verify")
+                        .help("Enable or disable GPG verification of commits")
+                        .action(ArgAction::Set)
+                        .value_parser(value_parser!(bool)),
+                )
+                .args(option_args()),
+        )
+        .subcommand(
+            Command::new("delete")
+                .about("Delete remote")
+                .arg(name_arg()),
+        )
+        .subcommand(Command::new("show").about("Show remote").arg(name_arg()))
+        .subcommand(
+            Command::new("set-url")
+                .about("Change remote url")
+                .arg(name_arg())
+                .arg(url_arg()),
+        )
+}
+
+fn name_arg() -> Arg {
+    Arg::new("name")
+        .help("Remote name")
+        .required(true)
+        .action(ArgAction::Set)
+        .value_parser(value_parser!(String))
+}
+
+/// Options shared by `add` and `modify`.
+fn option_args() -> [Arg; 4] {
+    [
+        Arg::new("gpg-import")
+            .long("gpg-import")
+            .help("Import GPG keys from file")
+            .action(ArgAction::Append)
+            .value_parser(value_parser!(PathBuf)),
+        Arg::new("branch")
+            .long("branch")
+            .help("Restrict remote to branch")
+            .action(ArgAction::Append)
+            .value_parser(value_parser!(String)),
+        Arg::new("contenturl")
+            .long("contenturl")
+            .help("Fetch content from a different url")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(String)),
+        Arg::new("set")
+            .long("set")
+            .help("Set remote option KEY=VALUE")
+            .action(ArgAction::Append)
+            .value_parser(value_parser!(String)),
+    ]
+}
+
+fn url_arg() -> Arg {
+    Arg::new("url")
+        .help("Remote url")
+        .required(true)
+        .action(ArgAction::Set)
+        .value_parser(value_parser!(String))
+}
+
+pub async fn run(
+    args: &ArgMatches,
+    engine: &Engine,
+    cancellable: &Cancellable,
+) -> Result<(), Error> {
+    let cancellable = Some(cancellable);
+    let format = Format::from_args(args);
+
+    match args.subcommand() {
+        Some(("list", _)) => {
+            let remotes = engine.remotes()?;
+            if format == Format::Json {
+                return output::print_json("remotes", &remotes);
+            }
+            for remote in remotes {
+                println!("{}\t{}", remote.name, remote.url);
+            }
+        }
+        Some(("add", args)) => {
+            let name = args.get_one::<String>("name").unwrap();
+            let url = args.get_one::<String>("url").unwrap();
+            let options = RemoteOptions {
+                contenturl: args.get_one::<String>("contenturl").cloned(),
+                gpg_verify: !args.get_flag("no-gpg-verify"),
+                branches: values(args, "branch"),
+                options: options(args)?,
+                gpg_keys: values(args, "gpg-import"),
+            };
+            let imported = engine.add_remote(name, url, &options, cancellable)?;
+            println!("Added remote {}", name);
+            if imported > 0 {
+                println!("Imported {} GPG keys", imported);
+            }
+        }
+        Some(("modify", args)) => {
+            let name = args.get_one::<String>("name").unwrap();
+            let branches = values::<String>(args, "branch");
+            let changes = RemoteChanges {
+                contenturl: args.get_one::<String>("contenturl").cloned(),
+                gpg_verify: args.get_one::<bool>("gpg-verify").copied(),
+                branches: (!branches.is_empty()).then_some(branches),
+                options: options(args)?,
+                gpg_keys: values(args, "gpg-import"),
+            };
+
+            let imported = engine.modify_remote(name, &changes, cancellable)?;
+            println!("Modified remote {}", name);
+            if imported > 0 {
+                println!("Imported {} GPG keys", imported);
+            }
+        }
+        Some(("delete", args)) => {
+            let name = args.get_one::<String>("name").unwrap();
+            engine.delete_remote(name, cancellable)?;
+            println!("Deleted remote {}", name);
+        }
+        Some(("show", args)) => {
+            let remote = engine.remote(args.get_one::<String>("name").unwrap())?;
+            if format == Format::Json {
+                return output::print_json("remote", &remote);
+            }
+            print_remote(&remote);
+        }
+        Some(("set-url", args)) => {
+            let name = args.get_one::<String>("name").unwrap();
+            let url = args.get_one::<String>("url").unwrap();
+            engine.set_remote_url(name, url, cancellable)?;
+            println!("Remote {} now points to {}", name, url);
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+fn values<T: Clone + Send + Sync + 'static>(args: &ArgMatches, id: &str) -> Vec<T> {
+    args.get_many::<T>(id)
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect()
+}
+
+/// `--set KEY=VALUE` options.
+fn options(args: &ArgMatches) -> Result<Vec<(String, String)>, Error> {
+    let mut options = Vec::new();
+    for option in args.get_many::<String>("set").into_iter().flatten() {
+        match option.split_once('=') {
+            Some((key, value)) => options.push((key.into(), value.into())),
+            None => return Err(Error::InvalidConfig("--set".into(), option.clone())),
+        }
+    }
+    Ok(options)
+}
+
+fn print_remote(remote: &Remote) {
+    println!("name               : {}", remote.name);
+    println!("url                : {}", remote.url);
+    if let Some(contenturl) = &remote.contenturl {
+        println!("contenturl         : {}", contenturl);
+    }
+    println!("gpg-verify         : {}", remote.gpg_verify);
+    println!("gpg-verify-summary : {}", remote.gpg_verify_summary);
+    println!("sign-verify        : {}", remote.sign_verify);
+    if !remote.branches.is_empty() {
+        println!("branches           : {}", remote.branches.join(", "));
+    }
+}
@@ -0,0 +1,57 @@
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use ostree::gio::Cancellable;
+
+use crate::{
+    engine::{Engine, Initiator, Target},
+    Error,
+};
+
+pub fn cmd() -> Command {
+    Command::new("rollback")
+        .about("Boot a previous deployment")
+        .long_about("Make a previous deployment the default boot entry")
+        .arg(
+            Arg::new("index")
+                .long("index")
+                .help("Deployment index as shown by status")
+                .action(ArgAction::Set)
+                .conflicts_with("revision")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("revision")
+                .long("revision")
+                .help("Deployment revision or its prefix")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(String)),
+        )
+}
+
+pub async fn run(
+    args: &ArgMatches,
+    engine: &Engine,
+    cancellable: &Cancellable,
+) -> Result<(), Error> {
+    let target = match (
+        args.get_one::<usize>("index"),
+        args.get_one::<String>("revision"),
+    ) {
+        (Some(index), _) => Target::Index(*index),
+        (_, Some(revision)) => Target::Revision(revision.clone()),
+        _ => Target::Rollback,
+    };
+
+    engine.lock()?;
+    let result = engine.record("rollback", Initiator::cli(), || {
+        engine.rollback(&target, Some(cancellable))
+    });
+    engine.unlock();
+
+    if result? {
+        println!("Rollback deployment is now the default, reboot to apply");
+    } else {
+        println!("Deployment is already the default");
+    }
+
+    Ok(())
+}
@@ -1,7 +1,20 @@
 use clap::{ArgMatches, Command};
+use serde::Serialize;
 // use ostree::{COMMIT_META_KEY_SOURCE_TITLE, COMMIT_META_KEY_VERSION, DeploymentUnlockedState};
 // use ostree::glib::{VariantDict, VariantTy};
-use crate::{engine::Engine, Error};
+use crate::{
+    cmd::output::{self, Format},
+    engine::{truncate, DeploymentState, Engine, Role},
+    Error,
+};
+
+#[derive(Serialize)]
+struct DeploymentStatus<'a> {
+    index: usize,
+    booted: bool,
+    #[serde(flatten)]
+    deployment: &'a DeploymentState,
+}
 
 pub fn cmd() -> Command {
     Command::new("status")
@@ -9,27 +22,56 @@ pub fn cmd() -> Command {
         .long_about("Check and apply system updates")
 }
 
-pub async fn run(_: &ArgMatches, engine: &Engine) -> Result<(), Error> {
-    let booted_deployment = engine.sysroot.booted_deployment();
-    for deployment in engine.states()?.iter() {
-        let status = match &booted_deployment {
-            Some(booted_deployment) => {
-                if booted_deployment.csum() == deployment.revision {
-                    "*"
-                } else {
-                    "-"
-                }
-            }
-            None => "-",
+pub async fn run(args: &ArgMatches, engine: &Engine) -> Result<(), Error> {
+    let deployments = engine.deployments()?;
+
+    if Format::from_args(args) == Format::Json {
+        let deployments = deployments
+            .iter()
+            .enumerate()
+            .map(|(index, deployment)| DeploymentStatus {
+                index,
+                booted: deployment.role == Role::Booted,
+                deployment,
+            })
+            .collect::<Vec<_>>();
+        return output::print_json("deployments", &deployments);
+    }
+
+    for (index, deployment) in deployments.iter().enumerate() {
+        let role = deployment.role;
+        let pinned = deployment.pinned;
+        let staged = deployment.staged;
+        let composition = &deployment.composition;
+        let deployment = &deployment.state;
+        let status = match role {
+            Role::Booted => "*",
+            Role::Pending => "+",
+            _ => "-",
+        };
+        let role = match role {
+            Role::Other => String::new(),
+            role => format!(" ({})", role.as_str()),
+        };
+        let role = match pinned {
+            true => format!("{role} [pinned]"),
+            false => role,
+        };
+        let role = match staged {
+            true => format!("{role} [staged]"),
+            false => role,
         };
         println!(
-            "{status} {}:{}",
+            "{status} {index}: {}:{}{role}",
             deployment.core.refspec,
-            truncate(&deployment.core.revision, 6)
+            truncate(&deployment.core.revision, 6),
         );
         println!("    merged    : {}", deployment.merged);
         println!("    revision  : {}", truncate(&deployment.revision, 6));
-        if deployment.extensions.len() > 0 {
+        if let Some(composition) = composition {
+            println!("    composed  : {}", truncate(composition, 6));
+        }
+        if !deployment.extensions.is_empty() {
             println!("    extensions: {}", deployment.extensions.len());
             for ext in &deployment.extensions {
                 println!("        - {}:{}", ext.refspec, truncate(&ext.revision, 6));
@@ -39,10 +81,3 @@ pub async fn run(_: &ArgMatches, engine: &Engine) -> Result<(), Error> {
 
     Ok(())
 }
-
-fn truncate(s: &str, max_chars: usize) -> &str {
-    match s.char_indices().nth(max_chars) {
-        None => s,
-        Some((idx, _)) => &s[..idx],
-    }
-}
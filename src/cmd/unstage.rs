@@ -0,0 +1,31 @@
+use clap::{ArgMatches, Command};
+use ostree::gio::Cancellable;
+
+use crate::{
+    engine::{Engine, Initiator},
+    Error,
+};
+
+pub fn cmd() -> Command {
+    Command::new("unstage")
+        .about("Drop staged deployment")
+        .long_about(
+            "Remove the deployment staged for the next shutdown, keeping the current boot entry",
+        )
+}
+
+pub async fn run(_: &ArgMatches, engine: &Engine, cancellable: &Cancellable) -> Result<(), Error> {
+    engine.lock()?;
+    let result = engine.record("unstage", Initiator::cli(), || {
+        engine.unstage(Some(cancellable))
+    });
+    engine.unlock();
+
+    if result? {
+        println!("Staged deployment removed");
+    } else {
+        println!("No staged deployment");
+    }
+
+    Ok(())
+}
@@ -1,14 +1,36 @@
+use std::path::PathBuf;
+
 use crate::{
-    engine::{Engine, RefData},
+    cmd::output::{self, Format},
+    engine::{local_url, ChangelogEntry, Engine, Estimate, Initiator, State},
     Error,
 };
 use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
-use ostree::gio::Cancellable;
+use ostree::{gio::Cancellable, AsyncProgress};
+use serde::Serialize;
 use tracing::info;
 
+#[derive(Serialize)]
+struct UpdateReport<'a> {
+    available: bool,
+    refs: &'a [ChangelogEntry],
+    /// Only estimated for `--check`, updates report as they go.
+    #[serde(flatten)]
+    estimate: Option<Estimate>,
+}
+
+/// Report of an update applied from a previous download, whose changelog
+/// was shown when it was downloaded.
+#[derive(Serialize)]
+struct DownloadedReport {
+    available: bool,
+    downloaded: bool,
+}
+
 pub fn cmd() -> Command {
     Command::new("update")
         .about("Update deployment")
+        .visible_alias("apply")
         .arg(
             Arg::new("include")
                 .short('i')
@@ -46,10 +68,21 @@ pub fn cmd() -> Command {
                 .help("Only check for updates don't apply")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("from")
+                .long("from")
+                .help("Pull from a local repository, verified with the remote's keys")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(PathBuf)),
+        )
 }
 
-pub async fn run(args: &ArgMatches, engine: &Engine) -> Result<(), Error> {
-    let cancellable = Cancellable::NONE;
+pub async fn run(
+    args: &ArgMatches,
+    engine: &Engine,
+    cancellable: &Cancellable,
+) -> Result<(), Error> {
+    let cancellable = Some(cancellable);
     let progress = crate::progress::get();
 
     let include = args
@@ -72,32 +105,113 @@ pub async fn run(args: &ArgMatches, engine: &Engine) -> Result<(), Error> {
     }
 
     for ext in include.iter() {
-        state.add_extension(ext);
+        state.add_extension(&engine.config, ext);
     }
 
     if let Some(channel) = args.get_one::<String>("channel") {
-        state.switch_channel(&channel);
+        state.switch_channel(&engine.config, channel);
     }
 
-    state
-        .extensions
-        .retain(|s| !exclude.contains(&s.get_data(RefData::Id)));
+    let format = Format::from_args(args);
+    let progress = match format {
+        Format::Text => Some(&progress),
+        Format::Json => None,
+    };
+    let from = match args.get_one::<PathBuf>("from") {
+        Some(path) => Some(local_url(path)?),
+        None => None,
+    };
+    let remote = match &from {
+        Some(url) => Some(url.as_str()),
+        None => args.get_one::<String>("remote").map(|s| s.as_str()),
+    };
+
+    let operation = if args.get_flag("check") {
+        "check"
+    } else if args.get_flag("reset") {
+        "reset"
+    } else if args.get_one::<String>("channel").is_some() {
+        "switch"
+    } else if !include.is_empty() || !exclude.is_empty() {
+        "extension"
+    } else {
+        "apply"
+    };
 
-    if state.extensions.len() > 0 {
-        state.merged = true;
+    engine.lock()?;
+    let result = engine.record(operation, Initiator::cli(), || {
+        engine
+            .resolve(&mut state, &exclude, remote, cancellable)
+            .and_then(|warnings| {
+                for warning in warnings {
+                    eprintln!("warning: {}", warning);
+                }
+                if state.extensions.len() > 0 {
+                    state.merged = true;
+                }
+                update(args, engine, &state, remote, format, progress, cancellable)
+            })
+    });
+    engine.unlock();
+
+    result
+}
+
+fn update(
+    args: &ArgMatches,
+    engine: &Engine,
+    state: &State,
+    remote: Option<&str>,
+    format: Format,
+    progress: Option<&AsyncProgress>,
+    cancellable: Option<&Cancellable>,
+) -> Result<(), Error> {
+    // Downloads came from the configured remote, not an explicit source.
+    if !args.get_flag("check") && remote.is_none() {
+        if let Some(true) = engine.apply_downloaded(state, cancellable)? {
+            match format {
+                Format::Json => output::print_json(
+                    "update",
+                    &DownloadedReport {
+                        available: true,
+                        downloaded: true,
+                    },
+                )?,
+                Format::Text => println!("Applied downloaded update"),
+            }
+            return Ok(());
+        }
     }
 
-    let (available, changelog) = engine.check(&state, Some(&progress), cancellable)?;
-    if available {
-        println!("New updates available");
-        println!("{}", changelog);
+    let estimate = args.get_flag("check");
+    let (available, changelog, estimate) =
+        engine.check(state, remote, estimate, progress, cancellable)?;
+    match format {
+        Format::Json => output::print_json(
+            "update",
+            &UpdateReport {
+                available,
+                refs: &changelog.entries,
+                estimate,
+            },
+        )?,
+        Format::Text if available => {
+            println!("New updates available");
+            println!("{}", changelog);
+            if let Some(estimate) = estimate {
+                println!("{}", estimate);
+            }
+        }
+        Format::Text => {}
+    }
 
+    if available {
         if args.get_flag("check") {
             return Ok(());
         }
 
         info!("Applying updates");
-        engine.apply(&state, Some(&progress), cancellable)?;
+        engine.apply(state, remote, progress, cancellable)?;
     } else {
         return Err(Error::NoUpdateAvailable);
     }
@@ -0,0 +1,566 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use ostree::glib::{self, KeyFile, KeyFileError, KeyFileFlags};
+
+use crate::Error;
+
+pub const DEFAULT_PATH: &str = "/etc/updates.conf";
+
+/// Shortest `[schedule] interval` accepted, so the daemon can't run back to
+/// back.
+pub const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Updater configuration, read from a GKeyFile with `core`, `refs`,
+/// `metadata`, `deployments`, `pull`, `verification`, `merge` and
+/// `schedule` groups. Missing keys fall back to the rlxos defaults.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub remote: String,
+    pub channel: String,
+    pub osname: String,
+    /// Directory for the journal and other persistent state, relative to the
+    /// sysroot.
+    pub state_dir: PathBuf,
+
+    pub core_ref: String,
+    pub extension_ref: String,
+    pub local_ref: String,
+    pub merged_ref: String,
+    pub pending_ref: String,
+
+    pub metadata_prefix: String,
+
+    /// Unpinned deployments kept after a deploy, 0 keeps all of them.
+    pub retain: usize,
+    /// Stage new deployments and let `ostree-finalize-staged` write them at
+    /// shutdown, so `/etc` is merged as late as possible.
+    pub staged: bool,
+
+    /// Whether pulls go through static deltas or individual objects.
+    pub deltas: DeltaPolicy,
+
+    /// Signatures pulled commits must carry, enforced by the pull itself.
+    pub verification: Verification,
+    /// ed25519 secret key used to sign the deployed local commits.
+    pub signing_key: Option<PathBuf>,
+
+    /// How paths shipped by more than one layer of a merged tree are
+    /// resolved.
+    pub merge_policy: MergePolicy,
+
+    /// What the daemon does on its own, every `interval` plus up to
+    /// `random_delay`.
+    pub schedule: Schedule,
+    pub interval: Duration,
+    pub random_delay: Duration,
+}
+
+/// Signatures required on pulled core and extension commits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verification {
+    /// Leave verification to the remote configuration.
+    Remote,
+    /// GPG signatures from the keys imported for the remote.
+    Gpg,
+    /// ed25519 signatures from the remote's `sign-verify` keys.
+    Sign,
+    /// Every method enabled on the remote, at least one of them.
+    Any,
+}
+
+/// Use of static deltas when pulling updates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeltaPolicy {
+    /// Use a delta when the remote has one, objects otherwise.
+    Auto,
+    /// Fail when the remote has no delta for an update.
+    Force,
+    /// Always pull individual objects.
+    Disable,
+    /// Require a delta, retrying with objects when it can't be used.
+    Fallback,
+}
+
+/// How to handle a path shipped by more than one layer of a merged tree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergePolicy {
+    /// Refuse to merge.
+    Error,
+    /// Keep the file of the extension whose id sorts last.
+    LastWins,
+    /// Layer extensions by the `priority` in their commit metadata, the
+    /// highest one wins. Ties are broken by id.
+    Priority,
+}
+
+/// Automatic updates run by the daemon.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Schedule {
+    /// Only act on client requests.
+    Off,
+    /// Check for updates and report them.
+    Check,
+    /// Also pull the objects of available updates.
+    Download,
+    /// Also deploy available updates for the next boot.
+    Apply,
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Schedule::Off),
+            "check" => Ok(Schedule::Check),
+            "download" => Ok(Schedule::Download),
+            "apply" => Ok(Schedule::Apply),
+            _ => Err(Error::InvalidConfig("schedule.policy".into(), s.into())),
+        }
+    }
+}
+
+impl FromStr for DeltaPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(DeltaPolicy::Auto),
+            "force" => Ok(DeltaPolicy::Force),
+            "disable" => Ok(DeltaPolicy::Disable),
+            "fallback" => Ok(DeltaPolicy::Fallback),
+            _ => Err(Error::InvalidConfig("pull.deltas".into(), s.into())),
+        }
+    }
+}
+
+impl FromStr for MergePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(MergePolicy::Error),
+            "last-wins" => Ok(MergePolicy::LastWins),
+            "priority" => Ok(MergePolicy::Priority),
+            _ => Err(Error::InvalidConfig("merge.conflicts".into(), s.into())),
+        }
+    }
+}
+
+impl FromStr for Verification {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "remote" => Ok(Verification::Remote),
+            "gpg" => Ok(Verification::Gpg),
+            "sign" => Ok(Verification::Sign),
+            "any" => Ok(Verification::Any),
+            _ => Err(Error::InvalidConfig("verification.policy".into(), s.into())),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            remote: "rlxos".into(),
+            channel: "stable".into(),
+            osname: "rlxos".into(),
+            state_dir: "/var/lib/updates".into(),
+            core_ref: "{arch}/os/{channel}".into(),
+            extension_ref: "{arch}/extension/{id}/{channel}".into(),
+            local_ref: "{arch}/os/local".into(),
+            merged_ref: "{arch}/os/merged/{id}".into(),
+            pending_ref: "{arch}/os/pending/{id}".into(),
+            metadata_prefix: "rlxos".into(),
+            retain: 0,
+            staged: false,
+            deltas: DeltaPolicy::Auto,
+            verification: Verification::Remote,
+            signing_key: None,
+            merge_policy: MergePolicy::LastWins,
+            schedule: Schedule::Off,
+            interval: Duration::from_secs(24 * 60 * 60),
+            random_delay: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl Config {
+    /// Load `path`, or `DEFAULT_PATH` when no path is given. Only an explicit
+    /// path is required to exist.
+    pub fn load(path: Option<&PathBuf>) -> Result<Config, Error> {
+        let path = match path {
+            Some(path) => path.clone(),
+            None => {
+                let path = PathBuf::from(DEFAULT_PATH);
+                if !path.exists() {
+                    return Ok(Config::default());
+                }
+                path
+            }
+        };
+        Config::from_file(&path)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, Error> {
+        let keyfile = KeyFile::new();
+        keyfile.load_from_file(path, KeyFileFlags::NONE)?;
+        Config::from_keyfile(&keyfile)
+    }
+
+    fn from_keyfile(keyfile: &KeyFile) -> Result<Config, Error> {
+        let defaults = Config::default();
+        Ok(Config {
+            remote: value(keyfile, "core", "remote", defaults.remote),
+            channel: value(keyfile, "core", "channel", defaults.channel),
+            osname: value(keyfile, "core", "osname", defaults.osname),
+            state_dir: match keyfile.string("core", "state-dir") {
+                Ok(path) => PathBuf::from(path.as_str()),
+                Err(_) => defaults.state_dir,
+            },
+            core_ref: value(keyfile, "refs", "core", defaults.core_ref),
+            extension_ref: value(keyfile, "refs", "extension", defaults.extension_ref),
+            local_ref: value(keyfile, "refs", "local", defaults.local_ref),
+            merged_ref: value(keyfile, "refs", "merged", defaults.merged_ref),
+            pending_ref: value(keyfile, "refs", "pending", defaults.pending_ref),
+            metadata_prefix: value(keyfile, "metadata", "prefix", defaults.metadata_prefix),
+            retain: typed(
+                keyfile,
+                "deployments",
+                "retain",
+                keyfile.uint64("deployments", "retain"),
+                defaults.retain as u64,
+            )? as usize,
+            staged: typed(
+                keyfile,
+                "deployments",
+                "staged",
+                keyfile.boolean("deployments", "staged"),
+                defaults.staged,
+            )?,
+            deltas: match keyfile.string("pull", "deltas") {
+                Ok(policy) => policy.parse()?,
+                Err(_) => defaults.deltas,
+            },
+            verification: match keyfile.string("verification", "policy") {
+                Ok(policy) => policy.parse()?,
+                Err(_) => defaults.verification,
+            },
+            signing_key: match keyfile.string("verification", "signing-key") {
+                Ok(path) => Some(PathBuf::from(path.as_str())),
+                Err(_) => defaults.signing_key,
+            },
+            merge_policy: match keyfile.string("merge", "conflicts") {
+                Ok(policy) => policy.parse()?,
+                Err(_) => defaults.merge_policy,
+            },
+            schedule: match keyfile.string("schedule", "policy") {
+                Ok(policy) => policy.parse()?,
+                Err(_) => defaults.schedule,
+            },
+            interval: match duration(keyfile, "interval", defaults.interval)? {
+                interval if interval < MIN_INTERVAL => {
+                    return Err(Error::InvalidConfig(
+                        "schedule.interval".to_string(),
+                        humantime::format_duration(interval).to_string(),
+                    ))
+                }
+                interval => interval,
+            },
+            random_delay: duration(keyfile, "random-delay", defaults.random_delay)?,
+        })
+    }
+
+    pub fn core_ref(&self, channel: &str) -> String {
+        expand(&self.core_ref, "", channel)
+    }
+
+    pub fn extension_ref(&self, id: &str, channel: &str) -> String {
+        expand(&self.extension_ref, id, channel)
+    }
+
+    pub fn local_ref(&self) -> String {
+        expand(&self.local_ref, "", "")
+    }
+
+    /// Ref recording the merged commit for the input key `key`.
+    pub fn merged_ref(&self, key: &str) -> String {
+        expand(&self.merged_ref, key, "")
+    }
+
+    /// Ref keeping the downloaded commit of `id` until it is deployed.
+    pub fn pending_ref(&self, id: &str) -> String {
+        expand(&self.pending_ref, id, "")
+    }
+
+    /// Metadata key holding the revision of `id` in a merged commit.
+    pub fn revision_key(&self, id: &str) -> String {
+        self.metadata_key(&format!("revision.{}", id))
+    }
+
+    /// Updater specific commit and summary metadata key `name`.
+    pub fn metadata_key(&self, name: &str) -> String {
+        format!("{}.{}", self.metadata_prefix, name)
+    }
+
+    /// Group used for updater keys in deployment origin files.
+    pub fn origin_group(&self) -> &str {
+        &self.metadata_prefix
+    }
+
+    /// Extract the extension id from `refspec` if it matches the extension
+    /// ref template. A leading `remote:` is ignored.
+    pub fn extension_id(&self, refspec: &str) -> Option<String> {
+        self.parse_extension_ref(refspec).map(|(id, _)| id)
+    }
+
+    /// Split `refspec` into extension id and channel if it matches the
+    /// extension ref template. A leading `remote:` is ignored.
+    pub fn parse_extension_ref(&self, refspec: &str) -> Option<(String, String)> {
+        let (id, channel) = parse_ref(&self.extension_ref, refspec)?;
+        id.map(|id| (id, channel))
+    }
+
+    /// Extract the channel from `refspec` if it matches the core ref
+    /// template. A leading `remote:` is ignored.
+    pub fn core_channel(&self, refspec: &str) -> Option<String> {
+        parse_ref(&self.core_ref, refspec).map(|(_, channel)| channel)
+    }
+
+    pub fn is_extension_ref(&self, refspec: &str) -> bool {
+        self.extension_id(refspec).is_some()
+    }
+}
+
+fn value(keyfile: &KeyFile, group: &str, key: &str, default: String) -> String {
+    match keyfile.string(group, key) {
+        Ok(value) => value.to_string(),
+        Err(_) => default,
+    }
+}
+
+/// `value` read from `group.key`, `default` when the key is missing and an
+/// error for a value of the wrong type.
+fn typed<T>(
+    keyfile: &KeyFile,
+    group: &str,
+    key: &str,
+    value: Result<T, glib::Error>,
+    default: T,
+) -> Result<T, Error> {
+    match value {
+        Ok(value) => Ok(value),
+        Err(error)
+            if error.matches(KeyFileError::KeyNotFound)
+                || error.matches(KeyFileError::GroupNotFound) =>
+        {
+            Ok(default)
+        }
+        Err(_) => Err(Error::InvalidConfig(
+            format!("{}.{}", group, key),
+            keyfile
+                .value(group, key)
+                .map(|value| value.to_string())
+                .unwrap_or_default(),
+        )),
+    }
+}
+
+fn duration(keyfile: &KeyFile, key: &str, default: Duration) -> Result<Duration, Error> {
+    match keyfile.string("schedule", key) {
+        Ok(value) => humantime::parse_duration(&value)
+            .map_err(|_| Error::InvalidConfig(format!("schedule.{}", key), value.to_string())),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Match `refspec` against `template`, returning the `{id}` and `{channel}`
+/// parts. A leading `remote:` is ignored.
+fn parse_ref(template: &str, refspec: &str) -> Option<(Option<String>, String)> {
+    let refspec = match refspec.split_once(':') {
+        Some((_, refspec)) => refspec,
+        None => refspec,
+    };
+    let template = template.split('/').collect::<Vec<_>>();
+    let parts = refspec.split('/').collect::<Vec<_>>();
+    if template.len() != parts.len() {
+        return None;
+    }
+
+    let mut id = None;
+    let mut channel = String::new();
+    for (template, part) in template.iter().zip(parts.iter()) {
+        match *template {
+            "{id}" => id = Some(part.to_string()),
+            "{channel}" => channel = part.to_string(),
+            "{arch}" if *part == env::consts::ARCH => {}
+            template if template == *part => {}
+            _ => return None,
+        }
+    }
+    Some((id, channel))
+}
+
+fn expand(template: &str, id: &str, channel: &str) -> String {
+    template
+        .replace("{arch}", env::consts::ARCH)
+        .replace("{id}", id)
+        .replace("{channel}", channel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &str) -> Result<Config, Error> {
+        let keyfile = KeyFile::new();
+        keyfile.load_from_data(data, KeyFileFlags::NONE).unwrap();
+        Config::from_keyfile(&keyfile)
+    }
+
+    #[test]
+    fn verification_policy() {
+        assert_eq!(parse("").unwrap().verification, Verification::Remote);
+        let config = parse("[verification]\npolicy=sign\n").unwrap();
+        assert_eq!(config.verification, Verification::Sign);
+        assert!(matches!(
+            parse("[verification]\npolicy=none\n"),
+            Err(Error::InvalidConfig(key, value)) if key == "verification.policy" && value == "none"
+        ));
+    }
+
+    #[test]
+    fn merge_policy() {
+        assert_eq!(parse("").unwrap().merge_policy, MergePolicy::LastWins);
+        let config = parse("[merge]\nconflicts=priority\n").unwrap();
+        assert_eq!(config.merge_policy, MergePolicy::Priority);
+        assert!(matches!(
+            parse("[merge]\nconflicts=first-wins\n"),
+            Err(Error::InvalidConfig(key, _)) if key == "merge.conflicts"
+        ));
+    }
+
+    #[test]
+    fn schedule() {
+        let config = parse("").unwrap();
+        assert_eq!(config.schedule, Schedule::Off);
+        assert_eq!(config.interval, Duration::from_secs(24 * 60 * 60));
+
+        let config =
+            parse("[schedule]\npolicy=download\ninterval=6h 30m\nrandom-delay=0s\n").unwrap();
+        assert_eq!(config.schedule, Schedule::Download);
+        assert_eq!(config.interval, Duration::from_secs(6 * 60 * 60 + 30 * 60));
+        assert_eq!(config.random_delay, Duration::ZERO);
+
+        assert!(matches!(
+            parse("[schedule]\npolicy=weekly\n"),
+            Err(Error::InvalidConfig(key, _)) if key == "schedule.policy"
+        ));
+        assert!(matches!(
+            parse("[schedule]\nrandom-delay=soon\n"),
+            Err(Error::InvalidConfig(key, value)) if key == "schedule.random-delay" && value == "soon"
+        ));
+    }
+
+    #[test]
+    fn schedule_interval_minimum() {
+        assert_eq!(
+            parse("[schedule]\ninterval=1m\n").unwrap().interval,
+            MIN_INTERVAL
+        );
+        for interval in ["0s", "59s"] {
+            assert!(matches!(
+                parse(&format!("[schedule]\ninterval={}\n", interval)),
+                Err(Error::InvalidConfig(key, _)) if key == "schedule.interval"
+            ));
+        }
+    }
+
+    #[test]
+    fn delta_policy() {
+        assert_eq!(parse("").unwrap().deltas, DeltaPolicy::Auto);
+        let config = parse("[pull]\ndeltas=fallback\n").unwrap();
+        assert_eq!(config.deltas, DeltaPolicy::Fallback);
+        assert!(matches!(
+            parse("[pull]\ndeltas=always\n"),
+            Err(Error::InvalidConfig(key, _)) if key == "pull.deltas"
+        ));
+    }
+
+    #[test]
+    fn expand_templates() {
+        let arch = env::consts::ARCH;
+        let config = Config::default();
+        assert_eq!(config.core_ref("beta"), format!("{arch}/os/beta"));
+        assert_eq!(
+            config.extension_ref("devel", "beta"),
+            format!("{arch}/extension/devel/beta")
+        );
+        assert_eq!(config.merged_ref("key"), format!("{arch}/os/merged/key"));
+    }
+
+    #[test]
+    fn parse_templates() {
+        let config = Config::default();
+        let extension = config.extension_ref("devel", "beta");
+        assert_eq!(
+            config.parse_extension_ref(&extension),
+            Some(("devel".to_string(), "beta".to_string()))
+        );
+        assert_eq!(
+            config.parse_extension_ref(&format!("mirror:{extension}")),
+            Some(("devel".to_string(), "beta".to_string()))
+        );
+        assert_eq!(config.parse_extension_ref(&config.core_ref("beta")), None);
+        assert_eq!(
+            config.parse_extension_ref("other/extension/devel/beta"),
+            None
+        );
+
+        assert_eq!(
+            config.core_channel(&config.core_ref("beta")),
+            Some("beta".to_string())
+        );
+        assert_eq!(config.core_channel(&extension), None);
+    }
+
+    #[test]
+    fn parse_custom_templates() {
+        let config = Config {
+            core_ref: "os/{channel}/base".into(),
+            extension_ref: "ext/{channel}/{id}".into(),
+            ..Config::default()
+        };
+        assert_eq!(
+            config.core_channel("os/beta/base"),
+            Some("beta".to_string())
+        );
+        assert_eq!(
+            config.parse_extension_ref("ext/beta/devel"),
+            Some(("devel".to_string(), "beta".to_string()))
+        );
+        assert_eq!(config.parse_extension_ref("ext/beta"), None);
+    }
+
+    #[test]
+    fn deployments() {
+        let config = parse("").unwrap();
+        assert_eq!((config.retain, config.staged), (0, false));
+        let config = parse("[deployments]\nretain=3\nstaged=true\n").unwrap();
+        assert_eq!((config.retain, config.staged), (3, true));
+
+        assert!(matches!(
+            parse("[deployments]\nretain=abc\n"),
+            Err(Error::InvalidConfig(key, value)) if key == "deployments.retain" && value == "abc"
+        ));
+        assert!(matches!(
+            parse("[deployments]\nstaged=maybe\n"),
+            Err(Error::InvalidConfig(key, value)) if key == "deployments.staged" && value == "maybe"
+        ));
+    }
+}
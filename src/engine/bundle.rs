@@ -0,0 +1,460 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use ostree::gio::{self, Cancellable};
+use ostree::glib::{Bytes, ToVariant, Variant, VariantDict, VariantTy};
+use ostree::prelude::*;
+use ostree::{Repo, StaticDeltaGenerateOpt};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::config::{Config, Verification};
+use crate::engine::remote::origin_policy;
+use crate::engine::state::{RefState, State};
+use crate::engine::verify::verify;
+use crate::Error;
+
+/// Leading bytes of every bundle file.
+const MAGIC: &[u8; 8] = b"UPDBNDL1";
+
+/// Upper bounds of the sizes read from a bundle before it is trusted, so a
+/// corrupt one can't make the import allocate arbitrary amounts of memory.
+const MAX_MANIFEST_SIZE: u64 = 16 * 1024 * 1024;
+const MAX_METADATA_SIZE: u64 = 1024 * 1024;
+
+/// Describes the content of a bundle and the state it deploys.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub state: State,
+    /// Commits in the order their data follows the manifest.
+    pub entries: Vec<BundleEntry>,
+}
+
+/// A commit stored in a bundle as a single file static delta.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleEntry {
+    pub refspec: String,
+    pub revision: String,
+    /// Revision the delta applies to, none for a delta from scratch.
+    pub from: Option<String>,
+    /// Size of the detached metadata holding the commit signatures.
+    pub metadata_size: u64,
+    pub delta_size: u64,
+}
+
+/// Write the commits of `state` to the bundle file `path`: the manifest,
+/// then the signatures and delta of every commit. With `base`, every input
+/// that `base` has at another revision is stored as a delta from it.
+pub fn create(
+    repo: &Repo,
+    config: &Config,
+    state: &State,
+    path: &Path,
+    base: Option<&State>,
+    cancellable: Option<&Cancellable>,
+) -> Result<Manifest, Error> {
+    let inputs = inputs(state).enumerate().map(|(index, input)| {
+        let from = base.and_then(|base| base_revision(config, base, input, index == 0));
+        (input, from)
+    });
+
+    let mut entries: Vec<BundleEntry> = Vec::new();
+    let mut parts: Vec<(Vec<u8>, PathBuf)> = Vec::new();
+    for (index, (input, from)) in inputs.enumerate() {
+        let metadata = match repo.read_commit_detached_metadata(&input.revision, cancellable)? {
+            Some(metadata) => metadata.data_as_bytes().to_vec(),
+            None => Vec::new(),
+        };
+
+        let delta = temporary(repo, index);
+        info!("Generating delta for {}", input.refspec);
+        let params = VariantDict::new(None);
+        params.insert("filename", delta.as_path());
+        params.insert("inline-parts", true);
+        let generated = repo.static_delta_generate(
+            StaticDeltaGenerateOpt::Major,
+            from,
+            &input.revision,
+            None,
+            Some(&params.to_variant()),
+            cancellable,
+        );
+        if let Err(error) = generated {
+            remove(&parts);
+            return Err(error.into());
+        }
+
+        entries.push(BundleEntry {
+            refspec: input.refspec.clone(),
+            revision: input.revision.clone(),
+            from: from.map(|from| from.to_string()),
+            metadata_size: metadata.len() as u64,
+            delta_size: fs::metadata(&delta)?.len(),
+        });
+        parts.push((metadata, delta));
+    }
+
+    let manifest = Manifest {
+        state: state.clone(),
+        entries,
+    };
+    let written = write(path, &manifest, &parts);
+    remove(&parts);
+    written?;
+    Ok(manifest)
+}
+
+/// Import the commits of the bundle at `path`, verify them with the keys of
+/// their origin remote and point their refs at them. Returns the state the
+/// bundle deploys.
+pub fn import(
+    repo: &Repo,
+    config: &Config,
+    path: &Path,
+    cancellable: Option<&Cancellable>,
+) -> Result<State, Error> {
+    let file = File::open(path)?;
+    let length = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let manifest = read_manifest(&mut reader, length, path)?;
+    // Refs only move to commits imported and verified below.
+    if !covered(&manifest) {
+        return Err(Error::InvalidBundle(path.display().to_string()));
+    }
+    let origin = manifest.state.remote(config).to_string();
+    // The remote configuration never sees bundle commits, so they must be
+    // signed whatever the policy leaves to it.
+    let policy = match origin_policy(repo, &origin, config.verification)? {
+        Verification::Remote => {
+            return Err(Error::VerificationFailed(
+                path.display().to_string(),
+                format!("no signature verification configured for {}", origin),
+            ))
+        }
+        policy => policy,
+    };
+
+    // Nothing imported is kept unless every commit verifies.
+    repo.prepare_transaction(cancellable)?;
+    if let Err(error) = import_entries(
+        repo,
+        &manifest,
+        &mut reader,
+        path,
+        &origin,
+        policy,
+        cancellable,
+    ) {
+        repo.abort_transaction(Cancellable::NONE)?;
+        return Err(error);
+    }
+    for input in inputs(&manifest.state) {
+        let (remote, refspec) = ostree::parse_refspec(&input.refspec)?;
+        repo.transaction_set_ref(
+            Some(remote.as_deref().unwrap_or(&origin)),
+            &refspec,
+            Some(&input.revision),
+        );
+    }
+    repo.commit_transaction(cancellable)?;
+    Ok(manifest.state)
+}
+
+/// Import and verify the commits following the manifest in `reader`. Must
+/// be called with a transaction prepared on `repo`.
+fn import_entries(
+    repo: &Repo,
+    manifest: &Manifest,
+    reader: &mut impl Read,
+    path: &Path,
+    origin: &str,
+    policy: Verification,
+    cancellable: Option<&Cancellable>,
+) -> Result<(), Error> {
+    for (index, entry) in manifest.entries.iter().enumerate() {
+        let mut metadata = vec![0; entry.metadata_size as usize];
+        reader.read_exact(&mut metadata)?;
+
+        let delta = temporary(repo, index);
+        let mut file = File::create(&delta)?;
+        let copied = io::copy(&mut reader.take(entry.delta_size), &mut file);
+        drop(file);
+        let imported = match copied {
+            Ok(size) if size == entry.delta_size => {
+                info!("Importing {} from bundle", entry.refspec);
+                repo.static_delta_execute_offline(&gio::File::for_path(&delta), false, cancellable)
+                    .map_err(Error::from)
+            }
+            Ok(_) => Err(Error::InvalidBundle(path.display().to_string())),
+            Err(error) => Err(error.into()),
+        };
+        let _ = fs::remove_file(&delta);
+        imported?;
+
+        if !metadata.is_empty() {
+            let metadata =
+                Variant::from_bytes_with_type(&Bytes::from_owned(metadata), VariantTy::VARDICT);
+            repo.write_commit_detached_metadata(&entry.revision, Some(&metadata), cancellable)?;
+        }
+        verify(repo, origin, policy, &entry.revision, cancellable)?;
+    }
+    Ok(())
+}
+
+/// Revision `base` has for the same input as `input`, the core or the
+/// extension with the same id. None when `base` lacks it or is already at
+/// the revision of `input`.
+fn base_revision<'a>(
+    config: &Config,
+    base: &'a State,
+    input: &RefState,
+    core: bool,
+) -> Option<&'a str> {
+    let revision = match core {
+        true => &base.core.revision,
+        false => {
+            let id = config.extension_id(&input.refspec)?;
+            &inputs(base)
+                .skip(1)
+                .find(|extension| config.extension_id(&extension.refspec).as_ref() == Some(&id))?
+                .revision
+        }
+    };
+    (!revision.is_empty() && *revision != input.revision).then_some(revision.as_str())
+}
+
+/// Whether every input of the manifest state is one of its entries.
+fn covered(manifest: &Manifest) -> bool {
+    inputs(&manifest.state).all(|input| {
+        manifest
+            .entries
+            .iter()
+            .any(|entry| entry.refspec == input.refspec && entry.revision == input.revision)
+    })
+}
+
+fn inputs(state: &State) -> impl Iterator<Item = &RefState> {
+    std::iter::once(&state.core).chain(state.extensions.iter())
+}
+
+fn write(path: &Path, manifest: &Manifest, parts: &[(Vec<u8>, PathBuf)]) -> Result<(), Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let data = serde_json::to_vec(manifest)?;
+    writer.write_all(MAGIC)?;
+    writer.write_all(&(data.len() as u64).to_be_bytes())?;
+    writer.write_all(&data)?;
+    for (metadata, delta) in parts {
+        writer.write_all(metadata)?;
+        io::copy(&mut File::open(delta)?, &mut writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read the manifest of a bundle of `length` bytes, checking that the
+/// commits it lists fill the rest of the file.
+fn read_manifest(reader: &mut impl Read, length: u64, path: &Path) -> Result<Manifest, Error> {
+    let invalid = || Error::InvalidBundle(path.display().to_string());
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic).map_err(|_| invalid())?;
+    if &magic != MAGIC {
+        return Err(invalid());
+    }
+
+    let mut size = [0; 8];
+    reader.read_exact(&mut size).map_err(|_| invalid())?;
+    let size = u64::from_be_bytes(size);
+    let header = (MAGIC.len() + size_of::<u64>()) as u64;
+    if size > MAX_MANIFEST_SIZE || size > length.saturating_sub(header) {
+        return Err(invalid());
+    }
+    let mut data = Vec::new();
+    reader
+        .take(size)
+        .read_to_end(&mut data)
+        .map_err(|_| invalid())?;
+    if data.len() as u64 != size {
+        return Err(invalid());
+    }
+    let manifest: Manifest = serde_json::from_slice(&data).map_err(|_| invalid())?;
+
+    let mut total = header + size;
+    for entry in &manifest.entries {
+        if entry.metadata_size > MAX_METADATA_SIZE {
+            return Err(invalid());
+        }
+        total = total
+            .checked_add(entry.metadata_size)
+            .and_then(|total| total.checked_add(entry.delta_size))
+            .ok_or_else(invalid)?;
+    }
+    if total != length {
+        return Err(invalid());
+    }
+    Ok(manifest)
+}
+
+/// Scratch file for a delta in the temporary directory of `repo`, on the
+/// same filesystem as the objects it holds.
+fn temporary(repo: &Repo, index: usize) -> PathBuf {
+    let root = repo.path().path().unwrap_or_else(std::env::temp_dir);
+    root.join("tmp")
+        .join(format!("updates-bundle-{}-{}", process::id(), index))
+}
+
+fn remove(parts: &[(Vec<u8>, PathBuf)]) {
+    for (_, delta) in parts {
+        let _ = fs::remove_file(delta);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(refspec: &str, revision: &str, metadata_size: u64, delta_size: u64) -> BundleEntry {
+        BundleEntry {
+            refspec: refspec.to_string(),
+            revision: revision.to_string(),
+            from: None,
+            metadata_size,
+            delta_size,
+        }
+    }
+
+    fn manifest_of(entries: Vec<BundleEntry>) -> Manifest {
+        Manifest {
+            state: State {
+                revision: String::new(),
+                core: RefState {
+                    refspec: "rlxos:os/stable".to_string(),
+                    revision: "c0".to_string(),
+                },
+                merged: false,
+                extensions: Vec::new(),
+            },
+            entries,
+        }
+    }
+
+    /// Bundle bytes with `manifest` followed by `data`.
+    fn bundle(manifest: &Manifest, data: &[u8]) -> Vec<u8> {
+        let manifest = serde_json::to_vec(manifest).unwrap();
+        let mut bundle = MAGIC.to_vec();
+        bundle.extend_from_slice(&(manifest.len() as u64).to_be_bytes());
+        bundle.extend_from_slice(&manifest);
+        bundle.extend_from_slice(data);
+        bundle
+    }
+
+    fn read(bundle: &[u8]) -> Result<Manifest, Error> {
+        read_manifest(&mut &bundle[..], bundle.len() as u64, Path::new("test"))
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let name = format!("updates-bundle-test-{}", process::id());
+        let path = std::env::temp_dir().join(&name);
+        let delta = std::env::temp_dir().join(format!("{}.delta", name));
+        fs::write(&delta, b"delta").unwrap();
+
+        let manifest = manifest_of(vec![entry("rlxos:os/stable", "c0", 3, 5)]);
+        let written = write(&path, &manifest, &[(b"sig".to_vec(), delta.clone())]);
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&delta).unwrap();
+        written.unwrap();
+
+        let mut reader = &data[..];
+        let read = read_manifest(&mut reader, data.len() as u64, &path).unwrap();
+        assert_eq!(reader, b"sigdelta");
+        assert_eq!(
+            serde_json::to_value(&read).unwrap(),
+            serde_json::to_value(&manifest).unwrap()
+        );
+    }
+
+    #[test]
+    fn manifest_sizes_match_file() {
+        let manifest = manifest_of(vec![entry("rlxos:os/stable", "c0", 3, 5)]);
+        assert!(read(&bundle(&manifest, b"sigdelta")).is_ok());
+        assert!(matches!(
+            read(&bundle(&manifest, b"sigdelt")),
+            Err(Error::InvalidBundle(_))
+        ));
+        assert!(matches!(
+            read(&bundle(&manifest, b"sigdeltas")),
+            Err(Error::InvalidBundle(_))
+        ));
+    }
+
+    #[test]
+    fn manifest_sizes_are_bounded() {
+        let mut huge = MAGIC.to_vec();
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(read(&huge), Err(Error::InvalidBundle(_))));
+
+        let manifest = manifest_of(vec![entry("rlxos:os/stable", "c0", 1, u64::MAX)]);
+        assert!(matches!(
+            read(&bundle(&manifest, b"")),
+            Err(Error::InvalidBundle(_))
+        ));
+
+        let metadata = vec![0; MAX_METADATA_SIZE as usize + 1];
+        let manifest = manifest_of(vec![entry(
+            "rlxos:os/stable",
+            "c0",
+            metadata.len() as u64,
+            0,
+        )]);
+        assert!(matches!(
+            read(&bundle(&manifest, &metadata)),
+            Err(Error::InvalidBundle(_))
+        ));
+    }
+
+    #[test]
+    fn base_revision_for_every_input() {
+        let config = Config::default();
+        let input = |refspec: String, revision: &str| RefState {
+            refspec,
+            revision: revision.to_string(),
+        };
+        let state = |core: &str, extensions: &[(&str, &str)]| State {
+            revision: String::new(),
+            core: input(config.core_ref("stable"), core),
+            merged: true,
+            extensions: extensions
+                .iter()
+                .map(|(id, revision)| input(config.extension_ref(id, "stable"), revision))
+                .collect(),
+        };
+        let base = state("c0", &[("devel", "d0"), ("sdk", "s0")]);
+        let new = state("c1", &[("sdk", "s1"), ("devel", "d0"), ("debug", "g0")]);
+
+        let revisions = inputs(&new)
+            .enumerate()
+            .map(|(index, input)| base_revision(&config, &base, input, index == 0))
+            .collect::<Vec<_>>();
+        assert_eq!(revisions, [Some("c0"), Some("s0"), None, None]);
+    }
+
+    #[test]
+    fn state_covered_by_entries() {
+        assert!(covered(&manifest_of(vec![entry(
+            "rlxos:os/stable",
+            "c0",
+            0,
+            0
+        )])));
+        assert!(!covered(&manifest_of(vec![entry(
+            "rlxos:os/stable",
+            "c1",
+            0,
+            0
+        )])));
+        assert!(!covered(&manifest_of(Vec::new())));
+    }
+}
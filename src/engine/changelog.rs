@@ -0,0 +1,218 @@
+use std::fmt;
+
+use ostree::glib::{Variant, VariantDict};
+use ostree::{ObjectType, Repo};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use zbus::zvariant::Type;
+
+use crate::Error;
+
+/// Parent commits fetched and listed between the old and new revision.
+pub const MAX_COMMITS: usize = 10;
+
+/// Changes pulled for every ref whose revision moved.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct Changelog {
+    pub entries: Vec<ChangelogEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Type)]
+pub struct ChangelogEntry {
+    pub refspec: String,
+    pub old_revision: String,
+    pub new_revision: String,
+    pub subject: String,
+    pub body: String,
+    pub version: String,
+    pub timestamp: u64,
+    /// Commits between `old_revision` and `new_revision`, newest first and
+    /// excluding both ends.
+    pub commits: Vec<Commit>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct Commit {
+    pub revision: String,
+    pub subject: String,
+    pub body: String,
+    pub version: String,
+    pub timestamp: u64,
+}
+
+impl Changelog {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn push(&mut self, entry: ChangelogEntry) {
+        self.entries.push(entry);
+    }
+}
+
+impl Commit {
+    pub fn from_variant(revision: &str, commit: &Variant) -> Commit {
+        let text = |index: usize| {
+            commit
+                .child_value(index)
+                .get::<String>()
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        };
+        let metadata = VariantDict::new(Some(&commit.child_value(0)));
+        let version = metadata
+            .lookup::<String>(&ostree::COMMIT_META_KEY_VERSION)
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+
+        Commit {
+            revision: revision.to_string(),
+            subject: text(3),
+            body: text(4),
+            version,
+            timestamp: ostree::commit_get_timestamp(commit),
+        }
+    }
+}
+
+impl ChangelogEntry {
+    /// Build the entry for `refspec` if it resolves to something other than
+    /// `old_revision`. Intermediate commits are collected by walking parents
+    /// for as long as they are available locally.
+    pub fn for_ref(
+        repo: &Repo,
+        refspec: &str,
+        old_revision: &str,
+    ) -> Result<Option<ChangelogEntry>, Error> {
+        info!("Getting changelog for {}", refspec);
+        let new_revision = match repo.resolve_rev(refspec, false)? {
+            Some(revision) => revision.to_string(),
+            None => return Err(Error::NoRevisionForRefSpec(refspec.into())),
+        };
+        if new_revision == old_revision {
+            return Ok(None);
+        }
+
+        info!("Updated revision {}", new_revision);
+
+        let commit = repo.load_variant(ObjectType::Commit, &new_revision)?;
+        let head = Commit::from_variant(&new_revision, &commit);
+
+        let mut commits: Vec<Commit> = Vec::new();
+        let mut parent = ostree::commit_get_parent(&commit);
+        while let Some(revision) = parent.take() {
+            if revision == old_revision || commits.len() >= MAX_COMMITS {
+                break;
+            }
+            let Ok(commit) = repo.load_variant(ObjectType::Commit, &revision) else {
+                break;
+            };
+            parent = ostree::commit_get_parent(&commit);
+            commits.push(Commit::from_variant(&revision, &commit));
+        }
+
+        Ok(Some(ChangelogEntry {
+            refspec: refspec.to_string(),
+            old_revision: old_revision.to_string(),
+            new_revision,
+            subject: head.subject,
+            body: head.body,
+            version: head.version,
+            timestamp: head.timestamp,
+            commits,
+        }))
+    }
+}
+
+impl fmt::Display for ChangelogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.refspec, self.subject)?;
+        if !self.version.is_empty() {
+            write!(f, " ({})", self.version)?;
+        }
+        writeln!(f)?;
+        if !self.body.is_empty() {
+            writeln!(f, "{}", self.body)?;
+        }
+        writeln!(f, "rev: {} -> {}", self.old_revision, self.new_revision)?;
+        for commit in &self.commits {
+            writeln!(
+                f,
+                "    {} {}",
+                truncate(&commit.revision, 6),
+                commit.subject
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Changelog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+/// First `max_chars` characters of `s`, used to shorten revisions.
+pub fn truncate(s: &str, max_chars: usize) -> &str {
+    match s.char_indices().nth(max_chars) {
+        None => s,
+        Some((idx, _)) => &s[..idx],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit(revision: &str, subject: &str) -> Commit {
+        Commit {
+            revision: revision.to_string(),
+            subject: subject.to_string(),
+            body: String::new(),
+            version: String::new(),
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn truncate_revisions() {
+        assert_eq!(truncate("0123456789", 6), "012345");
+        assert_eq!(truncate("0123", 6), "0123");
+        assert_eq!(truncate("", 6), "");
+        assert_eq!(truncate("äöüßéèà", 6), "äöüßéè");
+    }
+
+    #[test]
+    fn display_truncates_commits() {
+        let changelog = Changelog {
+            entries: vec![ChangelogEntry {
+                refspec: "rlxos:os/stable".to_string(),
+                old_revision: "aaaaaaaaaa".to_string(),
+                new_revision: "dddddddddd".to_string(),
+                subject: "Update".to_string(),
+                body: String::new(),
+                version: "2.1".to_string(),
+                timestamp: 0,
+                commits: vec![commit("cccccccccc", "Fix"), commit("bbbbbbbbbb", "Add")],
+            }],
+        };
+        let expected = [
+            "rlxos:os/stable: Update (2.1)",
+            "rev: aaaaaaaaaa -> dddddddddd",
+            "    cccccc Fix",
+            "    bbbbbb Add",
+            "",
+        ];
+        assert_eq!(changelog.to_string(), expected.join("\n"));
+    }
+}
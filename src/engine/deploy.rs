@@ -1,23 +1,38 @@
-use std::{env, ptr};
+use std::collections::BTreeMap;
+use std::ptr;
 
 use ostree::gio::Cancellable;
 use ostree::glib::translate::{from_glib_full, ToGlibPtr};
-use ostree::glib::{Cast, IsA, KeyFile, ToVariant, VariantDict};
-use ostree::{ffi, gio, glib, MutableTree, RepoFile, Sysroot, SysrootSimpleWriteDeploymentFlags};
-use tracing::info;
+use ostree::glib::{Cast, GString, IsA, KeyFile, ToVariant, Variant, VariantDict};
+use ostree::{
+    ffi, gio, glib, ObjectType, Repo, RepoFile, Sysroot, SysrootSimpleWriteDeploymentFlags,
+};
+use tracing::{info, warn};
 
+use crate::config::Config;
+use crate::engine::deployment::{prune, refuse_staged};
+use crate::engine::history::{self, write_local_commit};
+use crate::engine::merge::{merge_key, merge_timestamp, merge_tree, Conflict};
 use crate::engine::state::State;
+use crate::engine::state_path;
+use crate::engine::verify::sign;
 use crate::Error;
 
 pub fn deploy(
     sysroot: &Sysroot,
+    config: &Config,
     state: &State,
     cancellable: Option<&Cancellable>,
 ) -> Result<(), Error> {
     info!("deploying state {:?}", state);
+    // A new staged deployment replaces the staged one, writing the boot
+    // order directly would silently drop it.
+    if !config.staged {
+        refuse_staged(sysroot)?;
+    }
     let osname = match sysroot.booted_deployment() {
         Some(deployment) => deployment.osname(),
-        None => "rlxos".into(),
+        None => config.osname.as_str().into(),
     };
     let deployment = sysroot.merge_deployment(Some(&osname)).unwrap();
     let repo = sysroot.repo();
@@ -27,31 +42,20 @@ pub fn deploy(
     let origin: KeyFile;
 
     if state.merged {
-        let (options, extensions) = state.options();
+        let (options, extensions) = state.options(config);
 
-        repo.prepare_transaction(cancellable)?;
-        let mutable_tree = MutableTree::from_commit(&repo, &state.core.revision)?;
+        let deployment_refspec = config.local_ref();
 
-        for extension in &state.extensions {
-            let (object_to_commit, _) = repo.read_commit(&extension.refspec, cancellable)?;
-            repo.write_directory_to_mtree(&object_to_commit, &mutable_tree, None, cancellable)?;
-        }
-
-        let root = repo.write_mtree(&mutable_tree, cancellable)?;
-        let boot_meta = VariantDict::new(None);
-        commit_metadata_for_bootable(&root, &boot_meta, cancellable)?;
-
-        let root = root.downcast_ref::<RepoFile>().unwrap();
-        let commit_checksum = repo.write_commit(
-            None,
-            None,
-            None,
-            Some(&options.to_variant()),
-            &root,
-            cancellable,
-        )?;
-
-        let deployment_refspec = format!("{}/os/local", env::consts::ARCH);
+        repo.prepare_transaction(cancellable)?;
+        let commit_checksum = match merged_commit(&repo, config, state, options, cancellable)
+            .and_then(|composition| local_commit(&repo, config, &composition, cancellable))
+        {
+            Ok(commit_checksum) => commit_checksum,
+            Err(error) => {
+                repo.abort_transaction(Cancellable::NONE)?;
+                return Err(error);
+            }
+        };
         repo.transaction_set_ref(None, &deployment_refspec, Some(&commit_checksum));
         let _stats = repo.commit_transaction(cancellable)?;
 
@@ -59,57 +63,188 @@ pub fn deploy(
             .resolve_rev(&deployment_refspec, false)?
             .unwrap()
             .to_string();
+        let compositions = state_path(sysroot, config, "compositions");
+        if let Err(error) = history::record(&compositions, &repo, config, &revision) {
+            warn!("failed to record composition {}: {}", revision, error);
+        }
 
         origin = sysroot.origin_new_from_refspec(&deployment_refspec);
-        origin.set_string("rlxos", "extensions", &extensions);
-        origin.set_boolean("rlxos", "merged", true);
-        origin.set_string(
-            "rlxos",
-            "channel",
-            &state
-                .core
-                .refspec
-                .to_string()
-                .split("/")
-                .map(|s| s.to_string())
-                .collect::<Vec<String>>()
-                .last()
-                .unwrap(),
-        )
+        origin.set_string(config.origin_group(), "extensions", &extensions);
+        origin.set_boolean(config.origin_group(), "merged", true);
+        origin.set_string(config.origin_group(), "channel", &state.channel(config));
+        origin.set_string(config.origin_group(), "remote", state.remote(config));
     } else {
         revision = state.core.revision.clone();
         origin = sysroot.origin_new_from_refspec(&state.core.refspec);
-        origin.set_boolean("rlxos", "merged", false);
+        origin.set_boolean(config.origin_group(), "merged", false);
     }
 
     let opts = ostree::SysrootDeployTreeOpts {
         ..Default::default()
     };
 
-    let new_deployment = sysroot.deploy_tree_with_options(
-        Some(&osname),
-        &revision,
-        Some(&origin),
-        Some(&deployment),
-        Some(&opts),
-        cancellable,
-    )?;
+    if config.staged {
+        info!("Staging deployment");
+        sysroot.stage_tree_with_options(
+            Some(&osname),
+            &revision,
+            Some(&origin),
+            Some(&deployment),
+            &opts,
+            cancellable,
+        )?;
+    } else {
+        let new_deployment = sysroot.deploy_tree_with_options(
+            Some(&osname),
+            &revision,
+            Some(&origin),
+            Some(&deployment),
+            Some(&opts),
+            cancellable,
+        )?;
 
-    info!("Writing deployment");
-    let flags = SysrootSimpleWriteDeploymentFlags::NO_CLEAN;
-    sysroot.simple_write_deployment(
-        Some(&osname),
-        &new_deployment,
-        Some(&deployment),
-        flags,
-        cancellable,
-    )?;
+        info!("Writing deployment");
+        let flags = SysrootSimpleWriteDeploymentFlags::NO_CLEAN;
+        sysroot.simple_write_deployment(
+            Some(&osname),
+            &new_deployment,
+            Some(&deployment),
+            flags,
+            cancellable,
+        )?;
+    }
+
+    let pruned = prune(sysroot, config.retain, cancellable)?;
+    if pruned > 0 {
+        info!("Pruned {} old deployments", pruned);
+    }
+    prune_merged_refs(sysroot, config, cancellable)?;
 
     info!("Cleaning up");
     sysroot.cleanup(cancellable)?;
     Ok(())
 }
 
+/// Reuse the merged commit written earlier for the same inputs, or write a
+/// new one and record it under the merged ref of its input key. This commit
+/// is the reproducible one, equal inputs give the same checksum on every
+/// machine. The deployed local commit on top of it is not, and is what
+/// gets signed.
+fn merged_commit(
+    repo: &Repo,
+    config: &Config,
+    state: &State,
+    options: BTreeMap<String, Variant>,
+    cancellable: Option<&Cancellable>,
+) -> Result<GString, Error> {
+    let key = merge_key(config, state);
+    let merged_ref = config.merged_ref(&key);
+    if let Some(revision) = repo.resolve_rev(&merged_ref, true)? {
+        info!("Reusing merged commit {} for inputs {}", revision, key);
+        return Ok(revision);
+    }
+
+    let revision = write_merged_commit(repo, config, state, options, &key, cancellable)?;
+    repo.transaction_set_ref(None, &merged_ref, Some(&revision));
+    Ok(revision)
+}
+
+/// Commit for the local ref on top of its history, sharing the tree of
+/// `composition`. This is the commit that gets deployed and signed.
+fn local_commit(
+    repo: &Repo,
+    config: &Config,
+    composition: &str,
+    cancellable: Option<&Cancellable>,
+) -> Result<GString, Error> {
+    let revision = write_local_commit(repo, config, composition, cancellable)?;
+    if let Some(key) = &config.signing_key {
+        sign(repo, key, &revision, cancellable)?;
+    }
+    Ok(revision)
+}
+
+/// Drop merged refs whose inputs are no longer deployed, so they don't keep
+/// pruned compositions alive.
+fn prune_merged_refs(
+    sysroot: &Sysroot,
+    config: &Config,
+    cancellable: Option<&Cancellable>,
+) -> Result<(), Error> {
+    let repo = sysroot.repo();
+    let prefix = config.merged_ref("");
+    let deployed = sysroot
+        .deployments()
+        .iter()
+        .filter_map(|deployment| {
+            let commit = repo
+                .load_variant(ObjectType::Commit, &deployment.csum())
+                .ok()?;
+            VariantDict::new(Some(&commit.child_value(0)))
+                .lookup::<String>(&config.metadata_key("inputs"))
+                .ok()
+                .flatten()
+        })
+        .collect::<Vec<_>>();
+
+    for (name, _) in repo.list_refs(None, cancellable)? {
+        let Some(key) = name.strip_prefix(&prefix) else {
+            continue;
+        };
+        if !deployed.iter().any(|inputs| inputs == key) {
+            info!("Dropping merged ref {}", name);
+            repo.set_ref_immediate(None, &name, None, cancellable)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_merged_commit(
+    repo: &Repo,
+    config: &Config,
+    state: &State,
+    options: BTreeMap<String, Variant>,
+    key: &str,
+    cancellable: Option<&Cancellable>,
+) -> Result<GString, Error> {
+    let (root, conflicts) = merge_tree(repo, config, state, cancellable)?;
+    let metadata = merged_metadata(config, options, key, &conflicts);
+    let boot_meta = VariantDict::new(None);
+    commit_metadata_for_bootable(&root, &boot_meta, cancellable)?;
+
+    let root = root.downcast_ref::<RepoFile>().unwrap();
+    let commit_checksum = repo.write_commit_with_time(
+        None,
+        None,
+        None,
+        Some(&metadata),
+        root,
+        merge_timestamp(repo, state)?,
+        cancellable,
+    )?;
+
+    Ok(commit_checksum)
+}
+
+/// Metadata of the merged commit, an `a{sv}` built in key order: the
+/// revisions of its inputs from `State::options`, their key and the conflicts
+/// found while merging.
+fn merged_metadata(
+    config: &Config,
+    mut options: BTreeMap<String, Variant>,
+    key: &str,
+    conflicts: &[Conflict],
+) -> Variant {
+    options.insert(config.metadata_key("inputs"), key.to_variant());
+    if !conflicts.is_empty() {
+        options.insert(
+            config.metadata_key("merge-conflicts"),
+            Conflict::to_metadata(conflicts),
+        );
+    }
+    options.to_variant()
+}
+
 fn commit_metadata_for_bootable(
     root: &impl IsA<gio::File>,
     options: &VariantDict,
@@ -132,3 +267,63 @@ fn commit_metadata_for_bootable(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::state::RefState;
+
+    fn state(config: &Config, extensions: &[(&str, &str)]) -> State {
+        State {
+            revision: String::new(),
+            core: RefState {
+                refspec: config.core_ref("stable"),
+                revision: "core0".to_string(),
+            },
+            merged: true,
+            extensions: extensions
+                .iter()
+                .map(|(id, revision)| RefState {
+                    refspec: config.extension_ref(id, "stable"),
+                    revision: revision.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn conflict(path: &str, overridden: &str, winner: &str) -> Conflict {
+        Conflict {
+            path: path.to_string(),
+            overridden: overridden.to_string(),
+            winner: winner.to_string(),
+        }
+    }
+
+    #[test]
+    fn merged_metadata_ignores_input_order() {
+        let config = Config::default();
+        let metadata = |extensions: &[(&str, &str)], conflicts: &[Conflict]| {
+            let state = state(&config, extensions);
+            let (options, _) = state.options(&config);
+            merged_metadata(&config, options, &merge_key(&config, &state), conflicts)
+        };
+
+        let a = metadata(
+            &[("a", "a0"), ("b", "b0")],
+            &[
+                conflict("/usr/a", "core", "a"),
+                conflict("/usr/b", "a", "b"),
+            ],
+        );
+        let b = metadata(
+            &[("b", "b0"), ("a", "a0")],
+            &[
+                conflict("/usr/b", "a", "b"),
+                conflict("/usr/a", "core", "a"),
+            ],
+        );
+        assert_eq!(a, b);
+        assert_eq!(a.data(), b.data());
+        assert_ne!(a, metadata(&[("a", "a0"), ("b", "b1")], &[]));
+    }
+}
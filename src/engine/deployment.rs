@@ -0,0 +1,221 @@
+use ostree::gio::Cancellable;
+use ostree::{Deployment, Sysroot};
+use serde::Serialize;
+use tracing::info;
+
+use crate::engine::state::State;
+use crate::Error;
+
+/// Role of a deployment in the boot order.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Booted,
+    Pending,
+    Rollback,
+    Other,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Booted => "booted",
+            Role::Pending => "pending",
+            Role::Rollback => "rollback",
+            Role::Other => "",
+        }
+    }
+}
+
+/// State of a deployment along with its place in the boot order.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeploymentState {
+    pub role: Role,
+    pub pinned: bool,
+    /// Written by `ostree-finalize-staged` at shutdown.
+    pub staged: bool,
+    /// Reproducible merged commit the deployed local commit was made from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub composition: Option<String>,
+    #[serde(flatten)]
+    pub state: State,
+}
+
+/// Selects a deployment for operations like rollback.
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// The deployment `rollback` would boot by default: the booted one when an
+    /// update is pending, otherwise the previous deployment.
+    Rollback,
+    Index(usize),
+    Revision(String),
+}
+
+pub fn role(sysroot: &Sysroot, deployment: &Deployment) -> Role {
+    let is = |other: Option<Deployment>| match other {
+        Some(other) => other.equal(deployment),
+        None => false,
+    };
+
+    if is(sysroot.booted_deployment()) {
+        return Role::Booted;
+    }
+
+    let (pending, rollback) = sysroot.query_deployments_for(Some(&deployment.osname()));
+    if is(pending) {
+        Role::Pending
+    } else if is(rollback) {
+        Role::Rollback
+    } else {
+        Role::Other
+    }
+}
+
+pub fn find(sysroot: &Sysroot, target: &Target) -> Result<Deployment, Error> {
+    let deployments = sysroot.deployments();
+    let deployment = match target {
+        Target::Rollback => {
+            let booted = sysroot.booted_deployment();
+            let osname = booted.as_ref().map(|deployment| deployment.osname());
+            match sysroot.query_deployments_for(osname.as_deref()) {
+                (Some(_), _) => booted,
+                (None, rollback) => rollback,
+            }
+        }
+        Target::Index(index) => deployments.get(*index).cloned(),
+        Target::Revision(revision) => deployments
+            .into_iter()
+            .find(|deployment| deployment.csum().starts_with(revision.as_str())),
+    };
+
+    match deployment {
+        Some(deployment) => Ok(deployment),
+        None => match target {
+            Target::Rollback => Err(Error::NoPreviousDeployment),
+            Target::Index(index) => Err(Error::NoDeploymentFound(index.to_string())),
+            Target::Revision(revision) => Err(Error::NoDeploymentFound(revision.clone())),
+        },
+    }
+}
+
+/// Move `deployment` to the top of the boot order, keeping the relative order
+/// of the others. Returns false when it already is the default entry. Fails
+/// while a deployment is staged, ostree would drop it once it isn't first.
+pub fn rollback(
+    sysroot: &Sysroot,
+    deployment: &Deployment,
+    cancellable: Option<&Cancellable>,
+) -> Result<bool, Error> {
+    refuse_staged(sysroot)?;
+    let deployments = sysroot.deployments();
+    if let Some(first) = deployments.first() {
+        if first.equal(deployment) {
+            return Ok(false);
+        }
+    }
+
+    let (mut new_deployments, others): (Vec<_>, Vec<_>) = deployments
+        .into_iter()
+        .partition(|other| other.equal(deployment));
+    new_deployments.extend(others);
+
+    info!(
+        "Making {}.{} the default deployment",
+        deployment.csum(),
+        deployment.deployserial()
+    );
+    sysroot.write_deployments(&new_deployments, cancellable)?;
+
+    Ok(true)
+}
+
+pub fn set_pinned(sysroot: &Sysroot, deployment: &Deployment, pinned: bool) -> Result<bool, Error> {
+    if deployment.is_pinned() == pinned {
+        return Ok(false);
+    }
+
+    sysroot.deployment_set_pinned(deployment, pinned)?;
+    Ok(true)
+}
+
+/// Drop the staged deployment so the next boot keeps the current default.
+/// Returns false when nothing is staged.
+pub fn unstage(sysroot: &Sysroot, cancellable: Option<&Cancellable>) -> Result<bool, Error> {
+    let Some(staged) = sysroot.staged_deployment() else {
+        return Ok(false);
+    };
+
+    info!(
+        "Unstaging deployment {}.{}",
+        staged.csum(),
+        staged.deployserial()
+    );
+    let new_deployments = sysroot
+        .deployments()
+        .into_iter()
+        .filter(|deployment| !deployment.equal(&staged))
+        .collect::<Vec<_>>();
+    sysroot.write_deployments(&new_deployments, cancellable)?;
+
+    Ok(true)
+}
+
+/// Fail when a deployment is staged, for changes to the boot order that
+/// would drop it.
+pub fn refuse_staged(sysroot: &Sysroot) -> Result<(), Error> {
+    match sysroot.staged_deployment() {
+        Some(staged) => Err(Error::DeploymentStaged(format!(
+            "{}.{}",
+            staged.csum(),
+            staged.deployserial()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Drop unpinned deployments beyond the first `retain` ones in boot order.
+/// Pinned, booted and staged deployments are always kept, `retain` of 0
+/// disables pruning. Returns the number of deployments removed.
+pub fn prune(
+    sysroot: &Sysroot,
+    retain: usize,
+    cancellable: Option<&Cancellable>,
+) -> Result<usize, Error> {
+    if retain == 0 {
+        return Ok(0);
+    }
+
+    let booted = sysroot.booted_deployment();
+    let mut kept = 0;
+    let mut new_deployments: Vec<Deployment> = Vec::new();
+    let mut pruned: Vec<Deployment> = Vec::new();
+    for deployment in sysroot.deployments() {
+        let is_booted = match &booted {
+            Some(booted) => booted.equal(&deployment),
+            None => false,
+        };
+        if deployment.is_pinned() || deployment.is_staged() {
+            new_deployments.push(deployment);
+        } else if kept < retain || is_booted {
+            kept += 1;
+            new_deployments.push(deployment);
+        } else {
+            pruned.push(deployment);
+        }
+    }
+
+    if pruned.is_empty() {
+        return Ok(0);
+    }
+
+    for deployment in &pruned {
+        info!(
+            "Pruning deployment {}.{}",
+            deployment.csum(),
+            deployment.deployserial()
+        );
+    }
+    sysroot.write_deployments(&new_deployments, cancellable)?;
+
+    Ok(pruned.len())
+}
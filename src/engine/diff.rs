@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+use std::ptr;
+
+use ostree::gio::{Cancellable, FileQueryInfoFlags, FileType};
+use ostree::glib::translate::{from_glib_full, from_glib_none, FromGlibPtrContainer, ToGlibPtr};
+use ostree::prelude::*;
+use ostree::{ffi, gio, glib, DiffFlags, DiffItem, Repo};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::engine::merge::merge_tree;
+use crate::engine::state::State;
+use crate::Error;
+
+/// File level changes between two states, grouped by top-level directory.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Diff {
+    /// Deployment the update is compared with: `pending` when an update is
+    /// already deployed for the next boot, `booted` otherwise.
+    pub base: String,
+    pub base_revision: String,
+    pub directories: BTreeMap<String, DirectoryDiff>,
+    /// Bytes fetched from the remote to make the new state available.
+    pub download_size: u64,
+    /// Change in the size of regular files between the two trees.
+    pub disk_size_delta: i64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DirectoryDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.directories.is_empty()
+    }
+
+    fn directory(&mut self, path: &str) -> &mut DirectoryDiff {
+        let directory = match path.trim_start_matches('/').split_once('/') {
+            Some((directory, _)) => directory,
+            None => "/",
+        };
+        self.directories.entry(directory.to_string()).or_default()
+    }
+}
+
+/// Compare the trees of `from` and `to`. Every object of both states must
+/// already be available in `repo`; merged trees are composed in a throwaway
+/// transaction.
+pub fn diff(
+    repo: &Repo,
+    config: &Config,
+    from: &State,
+    to: &State,
+    cancellable: Option<&Cancellable>,
+) -> Result<Diff, Error> {
+    repo.prepare_transaction(cancellable)?;
+    let result = diff_trees(repo, config, from, to, cancellable);
+    repo.abort_transaction(Cancellable::NONE)?;
+
+    result
+}
+
+fn diff_trees(
+    repo: &Repo,
+    config: &Config,
+    from: &State,
+    to: &State,
+    cancellable: Option<&Cancellable>,
+) -> Result<Diff, Error> {
+    let from_root = tree(repo, config, from, cancellable)?;
+    let to_root = tree(repo, config, to, cancellable)?;
+    let (modified, removed, added) = diff_dirs(&from_root, &to_root, cancellable)?;
+
+    let mut diff = Diff::default();
+    for file in added {
+        let path = relative_path(&to_root, &file);
+        diff.disk_size_delta += file_size(&file, cancellable);
+        diff.directory(&path).added.push(path);
+    }
+    for file in removed {
+        let path = relative_path(&from_root, &file);
+        diff.disk_size_delta -= file_size(&file, cancellable);
+        diff.directory(&path).removed.push(path);
+    }
+    for item in modified {
+        let (src, src_info, target_info) = unsafe {
+            let item: *const ffi::OstreeDiffItem = item.to_glib_none().0;
+            let item = &*item;
+            (
+                from_glib_none::<_, gio::File>(item.src),
+                from_glib_none::<_, gio::FileInfo>(item.src_info),
+                from_glib_none::<_, gio::FileInfo>(item.target_info),
+            )
+        };
+        let path = relative_path(&from_root, &src);
+        diff.disk_size_delta += target_info.size() - src_info.size();
+        diff.directory(&path).modified.push(path);
+    }
+
+    Ok(diff)
+}
+
+fn tree(
+    repo: &Repo,
+    config: &Config,
+    state: &State,
+    cancellable: Option<&Cancellable>,
+) -> Result<gio::File, Error> {
+    if state.merged && state.revision.is_empty() {
+        let (root, _) = merge_tree(repo, config, state, cancellable)?;
+        return Ok(root);
+    }
+    let revision = match state.revision.is_empty() {
+        true => &state.core.revision,
+        false => &state.revision,
+    };
+    let (root, _) = repo.read_commit(revision, cancellable)?;
+    Ok(root)
+}
+
+fn relative_path(root: &gio::File, file: &gio::File) -> String {
+    match root.relative_path(file) {
+        Some(path) => format!("/{}", path.display()),
+        None => file.parse_name().to_string(),
+    }
+}
+
+/// Size of `file`, or of every regular file below it for directories.
+fn file_size(file: &gio::File, cancellable: Option<&Cancellable>) -> i64 {
+    let flags = FileQueryInfoFlags::NOFOLLOW_SYMLINKS;
+    let Ok(info) = file.query_info("standard::type,standard::size", flags, cancellable) else {
+        return 0;
+    };
+    if info.file_type() != FileType::Directory {
+        return info.size();
+    }
+
+    let Ok(children) = file.enumerate_children("standard::name", flags, cancellable) else {
+        return 0;
+    };
+    children
+        .filter_map(|child| child.ok())
+        .map(|child| file_size(&file.child(child.name()), cancellable))
+        .sum()
+}
+
+/// `ostree::diff_dirs` drops its output arrays, so call into libostree
+/// directly and take ownership of the results.
+#[allow(clippy::type_complexity)]
+fn diff_dirs(
+    a: &gio::File,
+    b: &gio::File,
+    cancellable: Option<&Cancellable>,
+) -> Result<(Vec<DiffItem>, Vec<gio::File>, Vec<gio::File>), glib::Error> {
+    unsafe {
+        let modified = glib::ffi::g_ptr_array_new();
+        let removed = glib::ffi::g_ptr_array_new();
+        let added = glib::ffi::g_ptr_array_new();
+        let mut error = ptr::null_mut();
+        let is_ok = ffi::ostree_diff_dirs(
+            DiffFlags::NONE.bits(),
+            a.to_glib_none().0,
+            b.to_glib_none().0,
+            modified,
+            removed,
+            added,
+            cancellable.to_glib_none().0,
+            &mut error,
+        );
+        assert_eq!(is_ok == glib::ffi::GFALSE, !error.is_null());
+
+        let modified: Vec<DiffItem> = FromGlibPtrContainer::from_glib_full(modified);
+        let removed: Vec<gio::File> = FromGlibPtrContainer::from_glib_full(removed);
+        let added: Vec<gio::File> = FromGlibPtrContainer::from_glib_full(added);
+
+        if error.is_null() {
+            Ok((modified, removed, added))
+        } else {
+            Err(from_glib_full(error))
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::fs;
+use std::path::Path;
+
+use ostree::gio::Cancellable;
+use ostree::{Repo, RepoCommitState};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::engine::state::{RefState, State};
+use crate::Error;
+
+/// Record `state` as downloaded at `path`. Its commits are kept under the
+/// pending refs so repository cleanups don't prune them before they are
+/// deployed.
+pub fn save(
+    repo: &Repo,
+    config: &Config,
+    path: &Path,
+    state: &State,
+    cancellable: Option<&Cancellable>,
+) -> Result<(), Error> {
+    clear(repo, config, path, cancellable)?;
+    for (id, input) in inputs(config, state) {
+        repo.set_ref_immediate(
+            None,
+            &config.pending_ref(&id),
+            Some(&input.revision),
+            cancellable,
+        )?;
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string(state)?)?;
+    info!("Recorded downloaded state {}", state.core.revision);
+    Ok(())
+}
+
+/// Drop the downloaded state at `path` along with its pending refs.
+pub fn clear(
+    repo: &Repo,
+    config: &Config,
+    path: &Path,
+    cancellable: Option<&Cancellable>,
+) -> Result<(), Error> {
+    let prefix = config.pending_ref("");
+    for (name, _) in repo.list_refs(None, cancellable)? {
+        if name.starts_with(&prefix) {
+            repo.set_ref_immediate(None, &name, None, cancellable)?;
+        }
+    }
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Downloaded update of `state`: a recorded state tracking the same refs
+/// whose commits are complete in `repo`.
+pub fn find(
+    repo: &Repo,
+    config: &Config,
+    path: &Path,
+    state: &State,
+) -> Result<Option<State>, Error> {
+    let Ok(data) = fs::read_to_string(path) else {
+        return Ok(None);
+    };
+    let downloaded: State = match serde_json::from_str(&data) {
+        Ok(downloaded) => downloaded,
+        Err(error) => {
+            warn!("ignoring downloaded state {:?}: {}", path, error);
+            return Ok(None);
+        }
+    };
+
+    let refspecs = |state: &State| {
+        let mut refspecs = inputs(config, state)
+            .into_iter()
+            .map(|(_, input)| input.refspec.clone())
+            .collect::<Vec<_>>();
+        refspecs.sort();
+        refspecs
+    };
+    if downloaded.merged != state.merged || refspecs(&downloaded) != refspecs(state) {
+        return Ok(None);
+    }
+
+    for (id, input) in inputs(config, &downloaded) {
+        let complete = match repo.load_commit(&input.revision) {
+            Ok((_, commit_state)) => !commit_state.contains(RepoCommitState::PARTIAL),
+            Err(_) => false,
+        };
+        if !complete {
+            warn!("downloaded commit of {} is incomplete", id);
+            return Ok(None);
+        }
+    }
+    Ok(Some(downloaded))
+}
+
+/// Core and extensions of `state` by id.
+fn inputs<'a>(config: &Config, state: &'a State) -> Vec<(String, &'a RefState)> {
+    let mut inputs = vec![("core".to_string(), &state.core)];
+    for extension in &state.extensions {
+        let id = config
+            .extension_id(&extension.refspec)
+            .unwrap_or_else(|| extension.refspec.clone());
+        inputs.push((id, extension));
+    }
+    inputs
+}
@@ -0,0 +1,189 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use humansize::{format_size, DECIMAL};
+use ostree::gio::{Cancellable, IOErrorEnum};
+use ostree::glib::VariantDict;
+use ostree::prelude::*;
+use ostree::{AsyncProgress, ObjectName, ObjectType, Repo};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::engine::changelog::Changelog;
+use crate::engine::extension;
+use crate::Error;
+
+/// Expected cost of pulling and deploying an update.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Estimate {
+    /// Bytes to fetch from the remote.
+    pub download_size: u64,
+    /// Bytes the missing objects take once unpacked into the repository, from
+    /// the object sizes recorded in the commits.
+    pub disk_size: u64,
+}
+
+impl Estimate {
+    fn add(&mut self, other: Estimate) {
+        self.download_size += other.download_size;
+        self.disk_size += other.disk_size;
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} to download, {} on disk",
+            format_size(self.download_size, DECIMAL),
+            format_size(self.disk_size, DECIMAL)
+        )
+    }
+}
+
+/// Summary metadata key listing the static deltas of a remote.
+const SUMMARY_DELTAS_KEY: &str = "ostree.static-deltas";
+
+/// Estimate the cost of every entry of `changelog`, whose commits must be
+/// available locally. Entries with a static delta from their old revision
+/// listed in the summary of `remote` are downloaded through it, and the
+/// sizes of their delta parts are fetched with a single dry run for all of
+/// them. The others, and every entry when `deltas` is false or the remote
+/// only publishes a delta index, count the missing objects instead. The disk
+/// size always comes from the object sizes recorded in the commits.
+pub fn estimate(
+    repo: &Repo,
+    remote: &str,
+    changelog: &Changelog,
+    deltas: bool,
+    cancellable: Option<&Cancellable>,
+) -> Result<Estimate, Error> {
+    let listed = match deltas && !changelog.entries.is_empty() {
+        true => listed_deltas(repo, remote, cancellable)?,
+        false => HashSet::new(),
+    };
+    let revisions = changelog
+        .entries
+        .iter()
+        .filter(|entry| listed.contains(&delta_name(&entry.old_revision, &entry.new_revision)))
+        .map(|entry| entry.new_revision.as_str())
+        .collect::<Vec<_>>();
+    let delta_size = match revisions.is_empty() {
+        true => None,
+        false => delta_size(repo, remote, &revisions, cancellable)?,
+    };
+
+    let mut estimate = Estimate::default();
+    for entry in &changelog.entries {
+        let mut objects = objects(repo, &entry.new_revision, cancellable)?;
+        if delta_size.is_some() && revisions.contains(&entry.new_revision.as_str()) {
+            objects.download_size = 0;
+        }
+        estimate.add(objects);
+    }
+    estimate.download_size += delta_size.unwrap_or(0);
+    info!("Estimated update size: {}", estimate);
+    Ok(estimate)
+}
+
+/// Names of the static deltas listed in the summary of `remote`.
+fn listed_deltas(
+    repo: &Repo,
+    remote: &str,
+    cancellable: Option<&Cancellable>,
+) -> Result<HashSet<String>, Error> {
+    let summary = extension::fetch_summary(repo, remote, cancellable)?;
+    let metadata = VariantDict::new(Some(&summary.child_value(1)));
+    let Some(deltas) = metadata.lookup_value(SUMMARY_DELTAS_KEY, None) else {
+        return Ok(HashSet::new());
+    };
+    Ok(deltas
+        .iter()
+        .map(|entry| entry.child_get::<String>(0))
+        .collect())
+}
+
+fn delta_name(from: &str, to: &str) -> String {
+    format!("{}-{}", from, to)
+}
+
+/// Total size of the delta parts a pull of `revisions` would fetch, from
+/// the superblocks downloaded by a dry run. Returns None when the remote
+/// can't serve all of them through deltas.
+fn delta_size(
+    repo: &Repo,
+    remote: &str,
+    revisions: &[&str],
+    cancellable: Option<&Cancellable>,
+) -> Result<Option<u64>, Error> {
+    let options = VariantDict::new(None);
+    options.insert("refs", revisions);
+    options.insert("dry-run", true);
+    options.insert("require-static-deltas", true);
+
+    let progress = AsyncProgress::new();
+    match repo.pull_with_options(remote, &options.to_variant(), Some(&progress), cancellable) {
+        Ok(_) => Ok(Some(progress.uint64("total-delta-part-size"))),
+        Err(error) if error.matches(IOErrorEnum::Cancelled) => Err(Error::Cancelled),
+        Err(error) => {
+            info!("Not estimating from static deltas: {}", error);
+            Ok(None)
+        }
+    }
+}
+
+/// Count the objects of `revision` missing from `repo`, using the sizes
+/// recorded in the commit metadata.
+fn objects(
+    repo: &Repo,
+    revision: &str,
+    cancellable: Option<&Cancellable>,
+) -> Result<Estimate, Error> {
+    let commit = repo.load_variant(ObjectType::Commit, revision)?;
+    let entries = match ostree::commit_get_object_sizes(&commit) {
+        Ok(entries) => entries,
+        Err(error) => {
+            warn!("No size information for {}: {}", revision, error);
+            return Ok(Estimate::default());
+        }
+    };
+
+    let mut estimate = Estimate::default();
+    for entry in entries {
+        if repo.has_object(entry.objtype(), &entry.checksum(), cancellable)? {
+            continue;
+        }
+        estimate.download_size += entry.archived();
+        estimate.disk_size += entry.unpacked();
+    }
+    Ok(estimate)
+}
+
+/// Archived size of the objects of `revision` that a pull of individual
+/// objects would have fetched on top of `from`. Returns None without size
+/// information in the commit metadata.
+pub fn object_pull_size(
+    repo: &Repo,
+    from: &str,
+    revision: &str,
+    cancellable: Option<&Cancellable>,
+) -> Result<Option<u64>, Error> {
+    let commit = repo.load_variant(ObjectType::Commit, revision)?;
+    let Ok(entries) = ostree::commit_get_object_sizes(&commit) else {
+        return Ok(None);
+    };
+
+    let present = match repo.traverse_commit(from, 0, cancellable) {
+        Ok(objects) => objects,
+        Err(error) => {
+            info!("Not counting objects of {}: {}", from, error);
+            HashSet::new()
+        }
+    };
+    let size = entries
+        .iter()
+        .filter(|entry| !present.contains(&ObjectName::new(entry.checksum(), entry.objtype())))
+        .map(|entry| entry.archived())
+        .sum();
+    Ok(Some(size))
+}
@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+
+use ostree::gio::Cancellable;
+use ostree::glib::{FromVariant, Variant, VariantDict, VariantTy};
+use ostree::{ObjectType, Repo};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::Error;
+
+/// Summary metadata key holding the commit timestamp, in big endian.
+const SUMMARY_TIMESTAMP_KEY: &str = "ostree.commit.timestamp";
+
+/// An extension available on the remote, described by the ref of the
+/// requested channel or, when missing there, the most recent one.
+#[derive(Debug, Clone, Serialize)]
+pub struct Extension {
+    pub id: String,
+    /// Every channel the extension is published on.
+    pub channels: Vec<String>,
+    pub channel: String,
+    pub revision: String,
+    pub timestamp: u64,
+    pub installed_size: u64,
+    pub description: String,
+    /// Ids of the extensions this one depends on.
+    pub requires: Vec<String>,
+    /// Ids of the extensions that can't be installed along with this one.
+    pub conflicts: Vec<String>,
+}
+
+/// A ref as listed in the summary of a remote.
+#[derive(Clone)]
+pub struct SummaryRef {
+    pub name: String,
+    pub revision: String,
+    pub metadata: VariantDict,
+}
+
+pub fn summary(
+    repo: &Repo,
+    remote: &str,
+    cancellable: Option<&Cancellable>,
+) -> Result<Vec<SummaryRef>, Error> {
+    let summary = fetch_summary(repo, remote, cancellable)?;
+    let mut refs: Vec<SummaryRef> = Vec::new();
+    for entry in summary.child_value(0).iter() {
+        let data = entry.child_value(1);
+        refs.push(SummaryRef {
+            name: entry.child_get::<String>(0),
+            revision: ostree::checksum_from_bytes_v(&data.child_value(1)).to_string(),
+            metadata: VariantDict::new(Some(&data.child_value(2))),
+        });
+    }
+    Ok(refs)
+}
+
+/// Summary of `remote`, from the repository cache when it is still current.
+pub fn fetch_summary(
+    repo: &Repo,
+    remote: &str,
+    cancellable: Option<&Cancellable>,
+) -> Result<Variant, Error> {
+    let (summary_bytes, _) = repo.remote_fetch_summary(remote, cancellable)?;
+    Ok(Variant::from_bytes_with_type(
+        &summary_bytes,
+        VariantTy::new("(a(s(taya{sv}))a{sv})").unwrap(),
+    ))
+}
+
+/// Build the extension catalog from the summary refs matching the extension
+/// ref template. Metadata is read from the summary first and from the commit
+/// when it is available locally.
+pub fn catalog(repo: &Repo, config: &Config, refs: &[SummaryRef], channel: &str) -> Vec<Extension> {
+    let mut grouped: BTreeMap<String, Vec<(String, &SummaryRef)>> = BTreeMap::new();
+    for summary_ref in refs {
+        if let Some((id, ref_channel)) = config.parse_extension_ref(&summary_ref.name) {
+            grouped
+                .entry(id)
+                .or_default()
+                .push((ref_channel, summary_ref));
+        }
+    }
+
+    let mut extensions: Vec<Extension> = Vec::new();
+    for (id, channels) in grouped {
+        let mut candidates = channels
+            .iter()
+            .map(|(ref_channel, summary_ref)| {
+                extension(repo, config, &id, ref_channel, summary_ref)
+            })
+            .collect::<Vec<_>>();
+        let index = match candidates.iter().position(|e| e.channel == channel) {
+            Some(index) => index,
+            None => candidates
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, e)| e.timestamp)
+                .map_or(0, |(index, _)| index),
+        };
+
+        let mut extension = candidates.swap_remove(index);
+        extension.channels = channels.iter().map(|(c, _)| c.clone()).collect();
+        extensions.push(extension);
+    }
+    extensions
+}
+
+fn extension(
+    repo: &Repo,
+    config: &Config,
+    id: &str,
+    channel: &str,
+    summary_ref: &SummaryRef,
+) -> Extension {
+    let commit = repo
+        .load_variant(ObjectType::Commit, &summary_ref.revision)
+        .ok();
+    let commit_metadata = commit
+        .as_ref()
+        .map(|commit| VariantDict::new(Some(&commit.child_value(0))));
+    let lookup = |key: &str| Metadata {
+        summary: &summary_ref.metadata,
+        commit: commit_metadata.as_ref(),
+        key: config.metadata_key(key),
+    };
+
+    let timestamp = match summary_ref.metadata.lookup::<u64>(SUMMARY_TIMESTAMP_KEY) {
+        Ok(Some(timestamp)) => u64::from_be(timestamp),
+        _ => commit.as_ref().map_or(0, ostree::commit_get_timestamp),
+    };
+
+    let installed_size = match lookup("installed-size").get::<u64>() {
+        Some(size) => size,
+        None => commit
+            .as_ref()
+            .and_then(|commit| ostree::commit_get_object_sizes(commit).ok())
+            .map_or(0, |entries| {
+                entries.iter().map(|entry| entry.unpacked()).sum()
+            }),
+    };
+
+    Extension {
+        id: id.to_string(),
+        channels: Vec::new(),
+        channel: channel.to_string(),
+        revision: summary_ref.revision.clone(),
+        timestamp,
+        installed_size,
+        description: lookup("description").get::<String>().unwrap_or_default(),
+        requires: lookup("requires").get::<Vec<String>>().unwrap_or_default(),
+        conflicts: lookup("conflicts").get::<Vec<String>>().unwrap_or_default(),
+    }
+}
+
+/// A metadata key looked up in the summary, then in the commit.
+struct Metadata<'a> {
+    summary: &'a VariantDict,
+    commit: Option<&'a VariantDict>,
+    key: String,
+}
+
+impl Metadata<'_> {
+    fn get<T: FromVariant>(&self) -> Option<T> {
+        match self.summary.lookup::<T>(&self.key) {
+            Ok(Some(value)) => Some(value),
+            _ => self
+                .commit
+                .and_then(|commit| commit.lookup::<T>(&self.key).ok().flatten()),
+        }
+    }
+}
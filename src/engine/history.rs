@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use ostree::gio::Cancellable;
+use ostree::glib::{Cast, GString, ToVariant, Variant, VariantDict};
+use ostree::{ObjectType, Repo, RepoFile};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::engine::changelog::{truncate, Commit};
+use crate::engine::journal;
+use crate::Error;
+
+/// A commit of the local ref along with the merged commit it was made from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Composition {
+    #[serde(flatten)]
+    pub commit: Commit,
+    /// The merged commit, reproducible from the same inputs on any machine.
+    pub composition: Option<String>,
+}
+
+/// Write a commit for the local ref on top of its current tip, sharing the
+/// tree and metadata of the merged commit `composition`. The subject and
+/// body describe how the inputs changed since the previous local commit.
+/// Its parent and timestamp differ between machines, so only the tree and
+/// `composition` can be compared across them.
+pub fn write_local_commit(
+    repo: &Repo,
+    config: &Config,
+    composition: &str,
+    cancellable: Option<&Cancellable>,
+) -> Result<GString, Error> {
+    let parent = repo.resolve_rev(&config.local_ref(), true)?;
+    let previous = match &parent {
+        Some(parent) => match repo.load_variant(ObjectType::Commit, parent) {
+            Ok(commit) => inputs(config, &commit.child_value(0)),
+            Err(_) => BTreeMap::new(),
+        },
+        None => BTreeMap::new(),
+    };
+
+    let commit = repo.load_variant(ObjectType::Commit, composition)?;
+    let metadata = VariantDict::new(Some(&commit.child_value(0)));
+    metadata.insert(&config.metadata_key("composition"), composition);
+    let current = inputs(config, &commit.child_value(0));
+    let (subject, body) = describe(&previous, &current);
+
+    let (root, _) = repo.read_commit(composition, cancellable)?;
+    let root = root.downcast_ref::<RepoFile>().unwrap();
+    Ok(repo.write_commit(
+        parent.as_deref(),
+        Some(&subject),
+        Some(&body),
+        Some(&metadata.to_variant()),
+        root,
+        cancellable,
+    )?)
+}
+
+/// Commits of the local ref, newest first. Repository cleanups prune the
+/// commit objects of compositions that are no longer deployed, so the walk
+/// ends at the first missing parent and older compositions are listed from
+/// the record at `path`. Only their description survives there, the trees
+/// of pruned compositions are gone.
+pub fn history(repo: &Repo, config: &Config, path: &Path) -> Result<Vec<Composition>, Error> {
+    let mut commits: Vec<Composition> = Vec::new();
+    let mut next = repo
+        .resolve_rev(&config.local_ref(), true)?
+        .map(|revision| revision.to_string());
+    while let Some(revision) = next.take() {
+        let Ok(commit) = repo.load_variant(ObjectType::Commit, &revision) else {
+            break;
+        };
+        next = ostree::commit_get_parent(&commit).map(|parent| parent.to_string());
+        commits.push(Composition {
+            commit: Commit::from_variant(&revision, &commit),
+            composition: composition_of(config, &commit),
+        });
+    }
+
+    for recorded in journal::read::<Composition>(path)? {
+        let revision = &recorded.commit.revision;
+        if !commits
+            .iter()
+            .any(|listed| &listed.commit.revision == revision)
+        {
+            commits.push(recorded);
+        }
+    }
+    Ok(commits)
+}
+
+/// Add the local commit `revision` to the record at `path`, so it stays in
+/// the history once its commit object is pruned.
+pub fn record(path: &Path, repo: &Repo, config: &Config, revision: &str) -> Result<(), Error> {
+    let commit = repo.load_variant(ObjectType::Commit, revision)?;
+    journal::append(
+        path,
+        &Composition {
+            commit: Commit::from_variant(revision, &commit),
+            composition: composition_of(config, &commit),
+        },
+    )
+}
+
+/// Merged commit the local commit `revision` was made from, None for
+/// commits not made by a merge.
+pub fn composition(repo: &Repo, config: &Config, revision: &str) -> Option<String> {
+    let commit = repo.load_variant(ObjectType::Commit, revision).ok()?;
+    composition_of(config, &commit)
+}
+
+fn composition_of(config: &Config, commit: &Variant) -> Option<String> {
+    VariantDict::new(Some(&commit.child_value(0)))
+        .lookup::<String>(&config.metadata_key("composition"))
+        .ok()
+        .flatten()
+}
+
+/// Core and extension revisions recorded in merged commit metadata, keyed
+/// by id.
+fn inputs(config: &Config, metadata: &Variant) -> BTreeMap<String, String> {
+    let prefix = config.revision_key("");
+    let mut inputs = BTreeMap::new();
+    for entry in metadata.iter() {
+        let Some(key) = entry.child_value(0).get::<String>() else {
+            continue;
+        };
+        let Some(id) = key.strip_prefix(&prefix) else {
+            continue;
+        };
+        if let Some(revision) = entry.child_value(1).as_variant() {
+            if let Some(revision) = revision.get::<String>() {
+                inputs.insert(id.to_string(), revision);
+            }
+        }
+    }
+    inputs
+}
+
+fn describe(
+    previous: &BTreeMap<String, String>,
+    current: &BTreeMap<String, String>,
+) -> (String, String) {
+    let extensions = current
+        .keys()
+        .filter(|id| *id != "core")
+        .cloned()
+        .collect::<Vec<_>>();
+    let core = current
+        .get("core")
+        .map(|r| truncate(r, 6))
+        .unwrap_or_default();
+    let subject = match extensions.is_empty() {
+        true => format!("Compose core {}", core),
+        false => format!("Compose core {} with {}", core, extensions.join(", ")),
+    };
+
+    let mut body: Vec<String> = Vec::new();
+    for (id, revision) in current {
+        match previous.get(id) {
+            None => body.push(format!("{}: added {}", id, truncate(revision, 6))),
+            Some(old) if old != revision => body.push(format!(
+                "{}: {} -> {}",
+                id,
+                truncate(old, 6),
+                truncate(revision, 6)
+            )),
+            Some(_) => {}
+        }
+    }
+    for id in previous.keys() {
+        if !current.contains_key(id) {
+            body.push(format!("{}: removed", id));
+        }
+    }
+
+    (subject, body.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revisions(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(id, revision)| (id.to_string(), revision.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn describe_first_composition() {
+        let (subject, body) = describe(&BTreeMap::new(), &revisions(&[("core", "0123456789")]));
+        assert_eq!(subject, "Compose core 012345");
+        assert_eq!(body, "core: added 012345");
+    }
+
+    #[test]
+    fn describe_changed_inputs() {
+        let previous = revisions(&[("core", "aaaaaaaa"), ("a", "bbbbbbbb"), ("c", "eeeeeeee")]);
+        let current = revisions(&[("core", "cccccccc"), ("a", "bbbbbbbb"), ("b", "dddddddd")]);
+        let (subject, body) = describe(&previous, &current);
+        assert_eq!(subject, "Compose core cccccc with a, b");
+        assert_eq!(body, "b: added dddddd\ncore: aaaaaa -> cccccc\nc: removed");
+    }
+}
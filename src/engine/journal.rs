@@ -0,0 +1,175 @@
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::engine::state::State;
+use crate::Error;
+
+/// Who asked for an operation.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "initiator", rename_all = "lowercase")]
+pub enum Initiator {
+    /// The command line, run as `uid`.
+    Cli { uid: u32 },
+    /// A D-Bus client connected as `uid`.
+    DBus { uid: u32 },
+    /// The daemon's own schedule.
+    Daemon { uid: u32 },
+}
+
+impl Initiator {
+    /// The current process, for operations started from the command line.
+    pub fn cli() -> Initiator {
+        Initiator::Cli {
+            uid: nix::unistd::getuid().as_raw(),
+        }
+    }
+
+    /// The daemon process, for operations it schedules itself.
+    pub fn daemon() -> Initiator {
+        Initiator::Daemon {
+            uid: nix::unistd::getuid().as_raw(),
+        }
+    }
+
+    pub fn uid(&self) -> u32 {
+        match self {
+            Initiator::Cli { uid } | Initiator::DBus { uid } | Initiator::Daemon { uid } => *uid,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Initiator::Cli { .. } => "cli",
+            Initiator::DBus { .. } => "dbus",
+            Initiator::Daemon { .. } => "daemon",
+        }
+    }
+}
+
+/// A recorded operation, with the state before and after it ran.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub timestamp: u64,
+    pub operation: String,
+    #[serde(flatten)]
+    pub initiator: Initiator,
+    pub old: Option<State>,
+    pub new: Option<State>,
+    pub success: bool,
+    /// Error messages, outermost first, empty on success.
+    pub errors: Vec<String>,
+}
+
+impl Entry {
+    pub fn new(operation: &str, initiator: Initiator, old: Option<State>) -> Entry {
+        Entry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+            operation: operation.to_string(),
+            initiator,
+            old,
+            new: None,
+            success: true,
+            errors: Vec::new(),
+        }
+    }
+}
+
+/// Append `entry` to the journal at `path`, one JSON object per line. A last
+/// line cut short by a crash is terminated first, so the entry stays readable.
+pub fn append<T: Serialize>(path: &Path, entry: &T) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?;
+    if file.metadata()?.len() > 0 {
+        let mut last = [0u8];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            writeln!(file)?;
+        }
+    }
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Journal entries at `path`, newest first. Lines that don't parse, like one
+/// cut short by a crash, are skipped.
+pub fn read<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, Error> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut entries: Vec<T> = Vec::new();
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<T>(line) {
+            Ok(entry) => entries.push(entry),
+            Err(error) => warn!("skipping line {} of {:?}: {}", number + 1, path, error),
+        }
+    }
+    entries.reverse();
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_skips_truncated_lines() {
+        let path = std::env::temp_dir().join(format!("updates-journal-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        append(&path, &Entry::new("check", Initiator::cli(), None)).unwrap();
+        append(&path, &Entry::new("apply", Initiator::daemon(), None)).unwrap();
+        // An append cut short by a crash.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "{{\"timestamp\":1,\"operation\":\"ap").unwrap();
+        drop(file);
+
+        let entries = read::<Entry>(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let operations = entries
+            .iter()
+            .map(|entry| entry.operation.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(operations, ["apply", "check"]);
+        assert_eq!(entries[0].initiator, Initiator::daemon());
+    }
+
+    #[test]
+    fn append_after_truncated_line() {
+        let path =
+            std::env::temp_dir().join(format!("updates-journal-truncated-{}", std::process::id()));
+        fs::write(&path, "{\"timestamp\":1,\"operation\":\"ap").unwrap();
+
+        append(&path, &Entry::new("check", Initiator::cli(), None)).unwrap();
+        let entries = read::<Entry>(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operation, "check");
+    }
+
+    #[test]
+    fn read_missing_journal() {
+        let path =
+            std::env::temp_dir().join(format!("updates-journal-missing-{}", std::process::id()));
+        assert!(read::<Entry>(&path).unwrap().is_empty());
+    }
+}
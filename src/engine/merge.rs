@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+
+use ostree::gio::{Cancellable, FileQueryInfoFlags, FileType};
+use ostree::glib::{self, Cast, ChecksumType, ToVariant, Variant, VariantDict};
+use ostree::prelude::*;
+use ostree::{gio, MutableTree, ObjectType, Repo, RepoFile};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::config::{Config, MergePolicy};
+use crate::engine::state::{RefState, State};
+use crate::Error;
+
+/// A path shipped by more than one layer of a merged tree.
+#[derive(Debug, Clone, Serialize)]
+pub struct Conflict {
+    pub path: String,
+    /// Layer whose file got replaced, `core` or an extension id.
+    pub overridden: String,
+    /// Layer whose file ended up in the merged tree.
+    pub winner: String,
+}
+
+impl Conflict {
+    /// Conflict report as stored in merged commit metadata, `a(sss)`, sorted
+    /// so it doesn't depend on the order the tree was walked in.
+    pub fn to_metadata(conflicts: &[Conflict]) -> Variant {
+        let mut entries = conflicts
+            .iter()
+            .map(|c| (c.path.clone(), c.overridden.clone(), c.winner.clone()))
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries.to_variant()
+    }
+}
+
+/// Layer the extensions of `state` onto its core tree and write the result,
+/// along with every file overlap found on the way. Overlaps are resolved by
+/// `config.merge_policy`. Must be called with a transaction prepared on
+/// `repo`.
+pub fn merge_tree(
+    repo: &Repo,
+    config: &Config,
+    state: &State,
+    cancellable: Option<&Cancellable>,
+) -> Result<(gio::File, Vec<Conflict>), Error> {
+    let mutable_tree = MutableTree::from_commit(repo, &state.core.revision)?;
+
+    let mut extensions = state.extensions.iter().collect::<Vec<_>>();
+    extensions.sort_by_key(|extension| extension_id(config, extension));
+    if config.merge_policy == MergePolicy::Priority {
+        extensions.sort_by_key(|extension| priority(repo, config, extension));
+    }
+
+    let mut owners: HashMap<String, String> = HashMap::new();
+    let mut conflicts: Vec<Conflict> = Vec::new();
+    for extension in extensions {
+        let id = extension_id(config, extension);
+        let (object_to_commit, _) = repo.read_commit(&extension.revision, cancellable)?;
+
+        let mut layer = Layer {
+            id: &id,
+            owners: &mut owners,
+            conflicts: &mut conflicts,
+        };
+        layer.scan(&object_to_commit, Some(&mutable_tree), "", cancellable)?;
+
+        repo.write_directory_to_mtree(&object_to_commit, &mutable_tree, None, cancellable)?;
+    }
+
+    if config.merge_policy == MergePolicy::Error {
+        if let Some(conflict) = conflicts.first() {
+            return Err(Error::MergeConflict(
+                conflict.path.clone(),
+                conflict.overridden.clone(),
+                conflict.winner.clone(),
+            ));
+        }
+    }
+    for conflict in &conflicts {
+        warn!(
+            "{} from {} overrides {}",
+            conflict.path, conflict.winner, conflict.overridden
+        );
+    }
+
+    Ok((repo.write_mtree(&mutable_tree, cancellable)?, conflicts))
+}
+
+/// Key identifying the inputs of a merge: the core and extension revisions
+/// and the merge policy. Equal keys produce identical merged commits.
+pub fn merge_key(config: &Config, state: &State) -> String {
+    let mut extensions = state
+        .extensions
+        .iter()
+        .map(|extension| (extension_id(config, extension), &extension.revision))
+        .collect::<Vec<_>>();
+    extensions.sort();
+
+    let mut inputs = format!(
+        "core={}\npolicy={:?}\n",
+        state.core.revision, config.merge_policy
+    );
+    for (id, revision) in extensions {
+        inputs.push_str(&format!("{}={}\n", id, revision));
+    }
+    glib::compute_checksum_for_data(ChecksumType::Sha256, inputs.as_bytes())
+        .unwrap()
+        .to_string()
+}
+
+/// Timestamp for the merged commit of `state`: the newest of its inputs, so
+/// it doesn't depend on when the merge happens.
+pub fn merge_timestamp(repo: &Repo, state: &State) -> Result<u64, Error> {
+    let mut timestamp = 0;
+    for input in std::iter::once(&state.core).chain(state.extensions.iter()) {
+        let commit = repo.load_variant(ObjectType::Commit, &input.revision)?;
+        timestamp = timestamp.max(ostree::commit_get_timestamp(&commit));
+    }
+    Ok(timestamp)
+}
+
+fn extension_id(config: &Config, extension: &RefState) -> String {
+    config
+        .extension_id(&extension.refspec)
+        .unwrap_or_else(|| extension.refspec.clone())
+}
+
+/// Merge priority of `extension` from its commit metadata, 0 when unset.
+fn priority(repo: &Repo, config: &Config, extension: &RefState) -> i32 {
+    let Ok(commit) = repo.load_variant(ObjectType::Commit, &extension.revision) else {
+        return 0;
+    };
+    VariantDict::new(Some(&commit.child_value(0)))
+        .lookup::<i32>(&config.metadata_key("priority"))
+        .ok()
+        .flatten()
+        .unwrap_or(0)
+}
+
+/// Walks an extension tree against the tree merged so far.
+struct Layer<'a> {
+    id: &'a str,
+    owners: &'a mut HashMap<String, String>,
+    conflicts: &'a mut Vec<Conflict>,
+}
+
+impl Layer<'_> {
+    fn scan(
+        &mut self,
+        directory: &gio::File,
+        merged: Option<&MutableTree>,
+        path: &str,
+        cancellable: Option<&Cancellable>,
+    ) -> Result<(), Error> {
+        let children = directory.enumerate_children(
+            "standard::name,standard::type",
+            FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+            cancellable,
+        )?;
+        for info in children {
+            let info = info?;
+            let name = info.name();
+            let name = name.to_string_lossy();
+            let child_path = format!("{}/{}", path, name);
+            let child = directory.child(name.as_ref());
+            let (file_checksum, subdir) = match merged.map(|tree| tree.lookup(&name)) {
+                Some(Ok(found)) => found,
+                _ => (None, None),
+            };
+
+            if info.file_type() == FileType::Directory {
+                if file_checksum.is_some() {
+                    self.conflict(&child_path);
+                }
+                self.scan(&child, subdir.as_ref(), &child_path, cancellable)?;
+                continue;
+            }
+
+            let checksum = child
+                .downcast_ref::<RepoFile>()
+                .map(|file| file.checksum().to_string());
+            let identical = match &file_checksum {
+                Some(existing) => Some(existing.to_string()) == checksum,
+                None => false,
+            };
+            if (file_checksum.is_some() || subdir.is_some()) && !identical {
+                self.conflict(&child_path);
+            }
+            self.owners.insert(child_path, self.id.to_string());
+        }
+        Ok(())
+    }
+
+    fn conflict(&mut self, path: &str) {
+        let overridden = match self.owners.get(path) {
+            Some(owner) => owner.clone(),
+            None => "core".to_string(),
+        };
+        self.conflicts.push(Conflict {
+            path: path.to_string(),
+            overridden,
+            winner: self.id.to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extension(config: &Config, id: &str, revision: &str) -> RefState {
+        RefState {
+            refspec: config.extension_ref(id, "stable"),
+            revision: revision.to_string(),
+        }
+    }
+
+    fn state(config: &Config, extensions: Vec<RefState>) -> State {
+        State {
+            revision: String::new(),
+            core: RefState {
+                refspec: config.core_ref("stable"),
+                revision: "c0".to_string(),
+            },
+            merged: true,
+            extensions,
+        }
+    }
+
+    #[test]
+    fn merge_key_ignores_extension_order() {
+        let config = Config::default();
+        let a = extension(&config, "a", "a0");
+        let b = extension(&config, "b", "b0");
+        assert_eq!(
+            merge_key(&config, &state(&config, vec![a.clone(), b.clone()])),
+            merge_key(&config, &state(&config, vec![b, a])),
+        );
+    }
+
+    #[test]
+    fn merge_key_changes_with_inputs() {
+        let config = Config::default();
+        let key = |config: &Config, id: &str, revision: &str| {
+            merge_key(
+                config,
+                &state(config, vec![extension(config, id, revision)]),
+            )
+        };
+        assert_eq!(key(&config, "a", "a0"), key(&config, "a", "a0"));
+        assert_ne!(key(&config, "a", "a0"), key(&config, "a", "a1"));
+        assert_ne!(key(&config, "a", "a0"), key(&config, "b", "a0"));
+
+        let priority = Config {
+            merge_policy: MergePolicy::Priority,
+            ..Config::default()
+        };
+        assert_ne!(key(&config, "a", "a0"), key(&priority, "a", "a0"));
+    }
+}
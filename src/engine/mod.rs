@@ -1,41 +1,61 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use ostree::gio::Cancellable;
-use ostree::glib::{Variant, VariantTy};
+use ostree::prelude::*;
 use ostree::{gio::File, AsyncProgress, Sysroot};
-use tracing::info;
+use tracing::{info, warn};
 
+use crate::config::{Config, DeltaPolicy};
 use crate::engine::deploy::deploy;
-use crate::engine::pull::pull;
-use crate::engine::state::State;
+pub use crate::engine::deployment::{DeploymentState, Role, Target};
+pub use crate::engine::diff::{DirectoryDiff, Diff};
+pub use crate::engine::bundle::{BundleEntry, Manifest};
+pub use crate::engine::estimate::Estimate;
+use crate::engine::extension::SummaryRef;
+pub use crate::engine::extension::Extension;
+pub use crate::engine::history::Composition;
+pub use crate::engine::journal::{read as read_journal, Entry, Initiator};
+pub use crate::engine::merge::Conflict;
+use crate::engine::pull::{pull, pull_revisions};
+pub use crate::engine::remote::{local_url, Remote, RemoteChanges, RemoteOptions};
+use crate::engine::remote::Source;
+pub use crate::engine::changelog::{truncate, Changelog, ChangelogEntry, Commit};
+pub use crate::engine::state::{RefState, State};
 use crate::Error;
 
+mod bundle;
+mod changelog;
 mod deploy;
+mod deployment;
+mod diff;
+mod download;
+mod estimate;
+mod extension;
+mod history;
+mod journal;
+mod merge;
 mod pull;
+mod remote;
+mod resolve;
 mod state;
-
-pub enum RefData {
-    Remote,
-    Arch,
-    Type,
-    Id,
-    Channel,
-}
+mod verify;
 
 #[derive(Debug)]
 pub struct Engine {
     pub sysroot: Sysroot,
+    pub config: Config,
 }
 
 impl Engine {
-    pub fn new(root: &PathBuf) -> Result<Engine, Error> {
+    pub fn new(root: &PathBuf, config: Option<&PathBuf>) -> Result<Engine, Error> {
+        let config = Config::load(config)?;
         let root_file = File::for_path(root);
         let sysroot = Sysroot::new(Some(&root_file));
 
         sysroot.set_mount_namespace_in_use();
         sysroot.load(Cancellable::NONE)?;
 
-        Ok(Engine { sysroot })
+        Ok(Engine { sysroot, config })
     }
 
     pub fn lock(&self) -> Result<(), Error> {
@@ -59,55 +79,306 @@ impl Engine {
             None => return Err(Error::NoPreviousDeployment),
         };
 
-        State::for_deployment(&self.sysroot.repo(), &merged_deployment)
+        State::for_deployment(&self.sysroot.repo(), &merged_deployment, &self.config)
+    }
+
+    pub fn booted_state(&self) -> Result<State, Error> {
+        match self.sysroot.booted_deployment() {
+            Some(deployment) => {
+                State::for_deployment(&self.sysroot.repo(), &deployment, &self.config)
+            }
+            None => Err(Error::NoBootDeployment),
+        }
     }
 
     pub fn states(&self) -> Result<Vec<State>, Error> {
         let mut states_list: Vec<State> = Vec::new();
         for deployment in self.sysroot.deployments() {
-            let state = State::for_deployment(&self.sysroot.repo(), &deployment)?;
+            let state = State::for_deployment(&self.sysroot.repo(), &deployment, &self.config)?;
             states_list.push(state);
         }
 
         Ok(states_list)
     }
 
+    /// States of all deployments in boot order, along with their role.
+    pub fn deployments(&self) -> Result<Vec<DeploymentState>, Error> {
+        let mut deployments: Vec<DeploymentState> = Vec::new();
+        for deployment in self.sysroot.deployments() {
+            deployments.push(DeploymentState {
+                role: deployment::role(&self.sysroot, &deployment),
+                pinned: deployment.is_pinned(),
+                staged: deployment.is_staged(),
+                composition: history::composition(
+                    &self.sysroot.repo(),
+                    &self.config,
+                    &deployment.csum(),
+                ),
+                state: State::for_deployment(&self.sysroot.repo(), &deployment, &self.config)?,
+            });
+        }
+
+        Ok(deployments)
+    }
+
+    /// Look for updates of `state` without pulling their content. Returns
+    /// whether any ref moved, the changelog and, when `estimate` is set, the
+    /// estimated update size.
     pub fn check(
         &self,
         state: &State,
+        remote: Option<&str>,
+        estimate: bool,
         progress: Option<&AsyncProgress>,
         cancellable: Option<&Cancellable>,
-    ) -> Result<(bool, String), Error> {
-        println!("state: {:?}", state);
+    ) -> Result<(bool, Changelog, Option<Estimate>), Error> {
+        info!("Checking state: {:?}", state);
+        let repo = self.sysroot.repo();
+        let source = self.source(state, remote)?;
         let (changed, changelog, _) = pull(
-            &self.sysroot.repo(),
+            &repo,
+            &self.config,
             &state,
-            None,
+            &source,
             true,
             progress,
             cancellable,
         )?;
+        let estimate = match estimate {
+            true => Some(estimate::estimate(
+                &repo,
+                &source.name,
+                &changelog,
+                self.config.deltas != DeltaPolicy::Disable,
+                cancellable,
+            )?),
+            false => None,
+        };
         self.sysroot.cleanup(cancellable)?;
-        Ok((changed, changelog))
+        Ok((changed, changelog, estimate))
+    }
+
+    /// Resolve the update of `state` like `check` and fetch the content of
+    /// the commits it moves to, without writing any ref or download record.
+    /// Returns the resolved state, its changelog and the number of bytes
+    /// transferred.
+    pub fn fetch(
+        &self,
+        state: &State,
+        remote: Option<&str>,
+        progress: Option<&AsyncProgress>,
+        cancellable: Option<&Cancellable>,
+    ) -> Result<(State, Changelog, u64), Error> {
+        let repo = self.sysroot.repo();
+        let source = self.source(state, remote)?;
+        let (_, changelog, state) =
+            pull(&repo, &self.config, state, &source, true, None, cancellable)?;
+        let revisions = changelog
+            .entries
+            .iter()
+            .map(|entry| entry.new_revision.clone())
+            .collect::<Vec<_>>();
+        if !revisions.is_empty() {
+            pull_revisions(
+                &repo,
+                &self.config,
+                &source,
+                &revisions,
+                false,
+                progress,
+                cancellable,
+            )?;
+            if let Some(progress) = progress {
+                progress.finish();
+                if crate::progress::rendered(progress) {
+                    println!();
+                }
+            }
+        }
+        let transferred = match progress {
+            Some(progress) => progress.uint64("bytes-transferred"),
+            None => 0,
+        };
+        Ok((state, changelog, transferred))
     }
 
+    /// File level differences between two states whose objects are available
+    /// locally.
+    pub fn diff(
+        &self,
+        from: &State,
+        to: &State,
+        cancellable: Option<&Cancellable>,
+    ) -> Result<Diff, Error> {
+        diff::diff(&self.sysroot.repo(), &self.config, from, to, cancellable)
+    }
+
+    /// Settle the extension set of `state` before pulling it: drop
+    /// duplicates and `excluded` ids, add dependencies and refuse conflicts.
+    /// Returns warnings for the user.
+    pub fn resolve(
+        &self,
+        state: &mut State,
+        excluded: &[String],
+        remote: Option<&str>,
+        cancellable: Option<&Cancellable>,
+    ) -> Result<Vec<String>, Error> {
+        if state.extensions.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let repo = self.sysroot.repo();
+        let source = self.source(state, remote)?;
+        let channel = state.channel(&self.config);
+        let refs = extension::summary(&repo, &source.name, cancellable)?;
+        let catalog = extension::catalog(&repo, &self.config, &refs, &channel);
+
+        resolve::resolve(&self.config, state, excluded, |id| {
+            match catalog.iter().find(|e| e.id == id && e.channel == channel) {
+                Some(extension) => {
+                    resolve::requirements(&repo, &self.config, &source, extension, cancellable)
+                }
+                None => Err(Error::ExtensionNotFound(id.to_string())),
+            }
+        })
+    }
+
+    /// Source to pull `state` from: `remote`, which may be a remote name or
+    /// a url, or the remote the state tracks.
+    fn source(&self, state: &State, remote: Option<&str>) -> Result<Source, Error> {
+        Source::new(&self.sysroot.repo(), state.remote(&self.config), remote)
+    }
+
+    /// Pull every object of the update of `state` and record it, so a later
+    /// `apply` deploys it without network access. Returns whether an update
+    /// was found, its changelog and the number of bytes transferred.
+    pub fn download(
+        &self,
+        state: &State,
+        remote: Option<&str>,
+        progress: Option<&AsyncProgress>,
+        cancellable: Option<&Cancellable>,
+    ) -> Result<(bool, Changelog, u64), Error> {
+        let repo = self.sysroot.repo();
+        let (changed, changelog, updated) = pull(
+            &repo,
+            &self.config,
+            state,
+            &self.source(state, remote)?,
+            false,
+            progress,
+            cancellable,
+        )?;
+
+        let path = self.state_path("download");
+        if changed {
+            download::save(&repo, &self.config, &path, &updated, cancellable)?;
+        } else {
+            download::clear(&repo, &self.config, &path, cancellable)?;
+        }
+        let transferred = match progress {
+            Some(progress) => progress.uint64("bytes-transferred"),
+            None => 0,
+        };
+        Ok((changed, changelog, transferred))
+    }
+
+    /// Deploy the update downloaded for `state` without network access.
+    /// Returns None when no complete download matches `state`.
+    pub fn apply_downloaded(
+        &self,
+        state: &State,
+        cancellable: Option<&Cancellable>,
+    ) -> Result<Option<bool>, Error> {
+        let repo = self.sysroot.repo();
+        let path = self.state_path("download");
+        let Some(downloaded) = download::find(&repo, &self.config, &path, state)? else {
+            return Ok(None);
+        };
+
+        let changed = !same_revisions(&downloaded, state);
+        if changed {
+            info!("Applying downloaded state {:?}", downloaded);
+            check_cancelled(cancellable)?;
+            deploy(&self.sysroot, &self.config, &downloaded, cancellable)?;
+        }
+        download::clear(&repo, &self.config, &path, cancellable)?;
+        Ok(Some(changed))
+    }
+
+    /// Write a bundle of `state` to `path`, pulling its updates first. With
+    /// `base`, commits are stored as deltas from the inputs of that
+    /// deployment.
+    pub fn create_bundle(
+        &self,
+        state: &State,
+        path: &Path,
+        base: Option<&Target>,
+        remote: Option<&str>,
+        progress: Option<&AsyncProgress>,
+        cancellable: Option<&Cancellable>,
+    ) -> Result<Manifest, Error> {
+        let repo = self.sysroot.repo();
+        let base = match base {
+            Some(target) => {
+                let deployment = deployment::find(&self.sysroot, target)?;
+                Some(State::for_deployment(&repo, &deployment, &self.config)?)
+            }
+            None => None,
+        };
+        let (_, _, state) = pull(
+            &repo,
+            &self.config,
+            state,
+            &self.source(state, remote)?,
+            false,
+            progress,
+            cancellable,
+        )?;
+        bundle::create(&repo, &self.config, &state, path, base.as_ref(), cancellable)
+    }
+
+    /// Import the bundle at `path` and deploy the state it describes.
+    /// Returns false when that state is already deployed.
+    pub fn apply_bundle(&self, path: &Path, cancellable: Option<&Cancellable>) -> Result<bool, Error> {
+        let state = bundle::import(&self.sysroot.repo(), &self.config, path, cancellable)?;
+        if same_revisions(&state, &self.state()?) {
+            return Ok(false);
+        }
+        check_cancelled(cancellable)?;
+        deploy(&self.sysroot, &self.config, &state, cancellable)?;
+        Ok(true)
+    }
+
+    /// Pull and deploy `state`. A complete download is deployed instead,
+    /// unless `remote` names the source to pull from, and nothing is pulled
+    /// when the download holds no update.
     pub fn apply(
         &self,
         state: &State,
+        remote: Option<&str>,
         progress: Option<&AsyncProgress>,
         cancellable: Option<&Cancellable>,
     ) -> Result<bool, Error> {
+        if remote.is_none() {
+            if let Some(changed) = self.apply_downloaded(state, cancellable)? {
+                return Ok(changed);
+            }
+        }
+
         let (changed, _, state) = pull(
             &self.sysroot.repo(),
+            &self.config,
             &state,
-            None,
+            &self.source(state, remote)?,
             false,
             progress,
             cancellable,
         )?;
         self.sysroot.cleanup(cancellable)?;
         if changed {
-            deploy(&self.sysroot, &state, cancellable)?;
+            check_cancelled(cancellable)?;
+            deploy(&self.sysroot, &self.config, &state, cancellable)?;
         }
         Ok(changed)
     }
@@ -115,23 +386,26 @@ impl Engine {
     pub fn switch(
         &self,
         channel: &str,
+        remote: Option<&str>,
         progress: Option<&AsyncProgress>,
         cancellable: Option<&Cancellable>,
     ) -> Result<bool, Error> {
         let mut updated_state = self.state()?.clone();
-        updated_state.switch_channel(channel);
+        updated_state.switch_channel(&self.config, channel);
         info!("Updated state: {:?}", updated_state);
 
         let (changed, _, state) = pull(
             &self.sysroot.repo(),
+            &self.config,
             &updated_state,
-            None,
+            &self.source(&updated_state, remote)?,
             false,
             progress,
             cancellable,
         )?;
         if changed {
-            deploy(&self.sysroot, &state, cancellable)?;
+            check_cancelled(cancellable)?;
+            deploy(&self.sysroot, &self.config, &state, cancellable)?;
         }
         Ok(changed)
     }
@@ -139,21 +413,24 @@ impl Engine {
     pub fn reset(
         &self,
         channel: &str,
+        remote: Option<&str>,
         progress: Option<&AsyncProgress>,
         cancellable: Option<&Cancellable>,
     ) -> Result<bool, Error> {
         let mut updated_state = self.state()?.clone();
-        updated_state.switch_channel(channel);
+        updated_state.switch_channel(&self.config, channel);
         let (changed, _, state) = pull(
             &self.sysroot.repo(),
+            &self.config,
             &updated_state,
-            None,
+            &self.source(&updated_state, remote)?,
             false,
             progress,
             cancellable,
         )?;
         if changed {
-            deploy(&self.sysroot, &state, cancellable)?;
+            check_cancelled(cancellable)?;
+            deploy(&self.sysroot, &self.config, &state, cancellable)?;
         }
         Ok(changed)
     }
@@ -161,6 +438,7 @@ impl Engine {
     pub fn add_extension(
         &self,
         extensions: Vec<String>,
+        remote: Option<&str>,
         progress: Option<&AsyncProgress>,
         cancellable: Option<&Cancellable>,
     ) -> Result<bool, Error> {
@@ -168,55 +446,192 @@ impl Engine {
         let mut updated_state = self.state()?.clone();
         for extension in extensions.iter() {
             if !extension.is_empty() {
-                updated_state.add_extension(extension);
+                updated_state.add_extension(&self.config, extension);
             }
         }
+        for warning in self.resolve(&mut updated_state, &[], remote, cancellable)? {
+            warn!("{}", warning);
+        }
 
         info!("Updated State: {:?}", updated_state);
 
         let (changed, _, state) = pull(
             &self.sysroot.repo(),
+            &self.config,
             &updated_state,
-            None,
+            &self.source(&updated_state, remote)?,
             false,
             progress,
             cancellable,
         )?;
         if changed {
-            deploy(&self.sysroot, &state, cancellable)?;
+            check_cancelled(cancellable)?;
+            deploy(&self.sysroot, &self.config, &state, cancellable)?;
         }
         Ok(changed)
     }
 
+    /// Drop the staged deployment, returns false when nothing is staged.
+    pub fn unstage(&self, cancellable: Option<&Cancellable>) -> Result<bool, Error> {
+        deployment::unstage(&self.sysroot, cancellable)
+    }
+
+    pub fn rollback(
+        &self,
+        target: &Target,
+        cancellable: Option<&Cancellable>,
+    ) -> Result<bool, Error> {
+        let deployment = deployment::find(&self.sysroot, target)?;
+        deployment::rollback(&self.sysroot, &deployment, cancellable)
+    }
+
+    /// Pin or unpin a deployment so it survives pruning, returns false when
+    /// it already was in the requested state.
+    pub fn pin(&self, target: &Target, pinned: bool) -> Result<bool, Error> {
+        let deployment = deployment::find(&self.sysroot, target)?;
+        deployment::set_pinned(&self.sysroot, &deployment, pinned)
+    }
+
     pub fn list(
         &self,
-        remote: Option<&String>,
+        remote: Option<&str>,
         cancellable: Option<&Cancellable>,
     ) -> Result<Vec<String>, Error> {
-        let repo = self.sysroot.repo();
-        let remote = match remote {
-            Some(remote) => remote.clone(),
-            None => {
-                let remotes = repo.remote_list();
-                if remotes.is_empty() {
-                    return Err(Error::NoRemoteFound);
-                }
-                remotes.first().unwrap().to_string()
-            }
+        let refs = self.summary(remote, cancellable)?;
+        Ok(refs.into_iter().map(|r| r.name).collect())
+    }
+
+    /// Extensions published on the remote, described for the channel of the
+    /// current deployment.
+    pub fn extensions(
+        &self,
+        remote: Option<&str>,
+        cancellable: Option<&Cancellable>,
+    ) -> Result<Vec<Extension>, Error> {
+        let refs = self.summary(remote, cancellable)?;
+        let channel = match self.state() {
+            Ok(state) => state.channel(&self.config),
+            Err(_) => self.config.channel.clone(),
+        };
+        Ok(extension::catalog(&self.sysroot.repo(), &self.config, &refs, &channel))
+    }
+
+    /// Run `operation` and record it in the journal along with the state
+    /// before and after it. Failing to write the journal is only logged.
+    pub fn record<T, F>(&self, name: &str, initiator: Initiator, operation: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        let mut entry = Entry::new(name, initiator, self.state().ok());
+        let result = operation();
+        entry.new = self.state().ok();
+        if let Err(error) = &result {
+            entry.success = false;
+            entry.errors = error.chain();
+        }
+
+        let path = self.state_path("journal");
+        if let Err(error) = journal::append(&path, &entry) {
+            warn!("failed to write journal {:?}: {}", path, error);
+        }
+        result
+    }
+
+    /// Recorded operations, newest first.
+    pub fn journal(&self) -> Result<Vec<Entry>, Error> {
+        journal::read(&self.state_path("journal"))
+    }
+
+    /// Path of `name` in the configured state directory of the sysroot.
+    pub fn state_path(&self, name: &str) -> PathBuf {
+        state_path(&self.sysroot, &self.config, name)
+    }
+
+    /// State of the deployment that boots next, when it isn't the booted one.
+    pub fn pending_state(&self) -> Result<Option<State>, Error> {
+        let Some(booted) = self.sysroot.booted_deployment() else {
+            return Ok(None);
         };
+        let (pending, _) = self.sysroot.query_deployments_for(Some(&booted.osname()));
+        match pending {
+            Some(pending) => Ok(Some(State::for_deployment(
+                &self.sysroot.repo(),
+                &pending,
+                &self.config,
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Revision of the deployment that boots next, when it isn't the booted
+    /// one.
+    pub fn pending(&self) -> Option<String> {
+        let booted = self.sysroot.booted_deployment()?;
+        let (pending, _) = self.sysroot.query_deployments_for(Some(&booted.osname()));
+        pending.map(|deployment| deployment.csum().to_string())
+    }
+
+    /// Merged compositions recorded on the local ref, newest first.
+    pub fn history(&self) -> Result<Vec<Composition>, Error> {
+        let path = self.state_path("compositions");
+        history::history(&self.sysroot.repo(), &self.config, &path)
+    }
 
-        let (summary_bytes, _) = repo.remote_fetch_summary(&remote, cancellable)?;
-        let summary = Variant::from_bytes_with_type(
-            &summary_bytes,
-            VariantTy::new("(a(s(taya{sv}))a{sv})").unwrap(),
-        );
-        let ref_map = summary.child_value(0);
-        let mut refs: Vec<String> = Vec::new();
-        for r in ref_map.iter() {
-            refs.push(r.child_get::<String>(0).clone());
+    fn summary(
+        &self,
+        remote: Option<&str>,
+        cancellable: Option<&Cancellable>,
+    ) -> Result<Vec<SummaryRef>, Error> {
+        let repo = self.sysroot.repo();
+        if remote.is_none() && repo.remote_list().is_empty() {
+            return Err(Error::NoRemoteFound);
         }
+        let source = Source::new(&repo, &self.config.remote, remote)?;
+        extension::summary(&repo, &source.name, cancellable)
+    }
 
-        Ok(refs)
+    pub fn remotes(&self) -> Result<Vec<Remote>, Error> {
+        remote::list(&self.sysroot.repo())
+    }
+
+    pub fn remote(&self, name: &str) -> Result<Remote, Error> {
+        remote::get(&self.sysroot.repo(), name)
+    }
+
+    /// Add a remote to the system repository, returns the number of GPG
+    /// keys imported for it.
+    pub fn add_remote(
+        &self,
+        name: &str,
+        url: &str,
+        options: &RemoteOptions,
+        cancellable: Option<&Cancellable>,
+    ) -> Result<u32, Error> {
+        remote::add(&self.sysroot.repo(), name, url, options, cancellable)
+    }
+
+    /// Change the configuration of a remote, returns the number of GPG keys
+    /// imported for it.
+    pub fn modify_remote(
+        &self,
+        name: &str,
+        changes: &RemoteChanges,
+        cancellable: Option<&Cancellable>,
+    ) -> Result<u32, Error> {
+        remote::modify(&self.sysroot.repo(), name, changes, cancellable)
+    }
+
+    pub fn delete_remote(&self, name: &str, cancellable: Option<&Cancellable>) -> Result<(), Error> {
+        remote::delete(&self.sysroot.repo(), name, cancellable)
+    }
+
+    pub fn set_remote_url(
+        &self,
+        name: &str,
+        url: &str,
+        cancellable: Option<&Cancellable>,
+    ) -> Result<(), Error> {
+        remote::set_url(&self.sysroot.repo(), name, url, cancellable)
     }
 
     pub fn add_overlay(&self) -> Result<(), Error> {
@@ -235,3 +650,35 @@ impl Engine {
         Ok(())
     }
 }
+
+/// Path of `name` in the state directory `config` sets for `sysroot`.
+pub(crate) fn state_path(sysroot: &Sysroot, config: &Config, name: &str) -> PathBuf {
+    let root = sysroot.path().path().unwrap_or_else(|| PathBuf::from("/"));
+    let state_dir = &config.state_dir;
+    root.join(state_dir.strip_prefix("/").unwrap_or(state_dir))
+        .join(name)
+}
+
+/// Whether `a` and `b` are made of the same core and extension commits.
+fn same_revisions(a: &State, b: &State) -> bool {
+    let revisions = |state: &State| {
+        let mut revisions = state
+            .extensions
+            .iter()
+            .map(|extension| extension.revision.clone())
+            .collect::<Vec<_>>();
+        revisions.sort();
+        revisions.push(state.core.revision.clone());
+        revisions
+    };
+    revisions(a) == revisions(b)
+}
+
+/// Fail with `Error::Cancelled` once `cancellable` has been triggered, so
+/// callers can stop between pulling and deploying.
+pub fn check_cancelled(cancellable: Option<&Cancellable>) -> Result<(), Error> {
+    match cancellable {
+        Some(cancellable) if cancellable.is_cancelled() => Err(Error::Cancelled),
+        _ => Ok(()),
+    }
+}
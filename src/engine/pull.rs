@@ -1,29 +1,127 @@
-use ostree::gio::Cancellable;
+use std::fmt;
+
+use humansize::{format_size, DECIMAL};
+use ostree::gio::{Cancellable, IOErrorEnum};
 use ostree::glib::VariantDict;
 use ostree::prelude::*;
 use ostree::{AsyncProgress, Repo, RepoPullFlags};
-use tracing::info;
+use serde::Serialize;
+use tracing::{info, warn};
 
+use crate::config::{Config, DeltaPolicy, Verification};
+use crate::engine::changelog::{self, Changelog, ChangelogEntry};
+use crate::engine::estimate;
+use crate::engine::remote::{self, Source};
 use crate::engine::state::{RefState, State};
+use crate::engine::verify::verify;
 use crate::Error;
 
+/// How the content of a pull was fetched.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Transfer {
+    /// Whether static deltas were used.
+    pub delta: bool,
+    /// Whether a required delta failed and objects were pulled instead.
+    pub fallback: bool,
+    /// Bytes fetched from the remote.
+    pub transferred: u64,
+    /// Bytes a pull of individual objects would have fetched, None when the
+    /// commits carry no size information.
+    pub objects_size: Option<u64>,
+}
+
+impl Transfer {
+    /// Bytes saved by the deltas compared with a pull of objects.
+    pub fn saved(&self) -> Option<u64> {
+        match self.delta {
+            true => self
+                .objects_size
+                .map(|size| size.saturating_sub(self.transferred)),
+            false => None,
+        }
+    }
+}
+
+impl fmt::Display for Transfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let transferred = format_size(self.transferred, DECIMAL);
+        match (self.delta, self.saved()) {
+            (true, Some(saved)) => write!(
+                f,
+                "{} transferred with static deltas, {} saved",
+                transferred,
+                format_size(saved, DECIMAL)
+            ),
+            (true, None) => write!(f, "{} transferred with static deltas", transferred),
+            (false, _) if self.fallback => write!(
+                f,
+                "{} transferred as objects after static deltas failed",
+                transferred
+            ),
+            (false, _) => write!(f, "{} transferred as objects", transferred),
+        }
+    }
+}
+
+/// Fetch only the commit `revision` from `source`, enough to read its
+/// metadata. No ref is written, and the commit is verified before it is
+/// used.
+pub fn pull_commit(
+    repo: &Repo,
+    config: &Config,
+    source: &Source,
+    revision: &str,
+    cancellable: Option<&Cancellable>,
+) -> Result<(), Error> {
+    let revisions = [revision.to_string()];
+    pull_revisions(repo, config, source, &revisions, true, None, cancellable)
+}
+
+/// Fetch the commits `revisions` from `source` along with their content, or
+/// only the commit objects with `commit_only`. No ref is written, and the
+/// commits are verified before they are used.
+pub fn pull_revisions(
+    repo: &Repo,
+    config: &Config,
+    source: &Source,
+    revisions: &[String],
+    commit_only: bool,
+    progress: Option<&AsyncProgress>,
+    cancellable: Option<&Cancellable>,
+) -> Result<(), Error> {
+    let options = VariantDict::new(None);
+    let flags = match commit_only {
+        true => RepoPullFlags::COMMIT_ONLY,
+        false => RepoPullFlags::NONE,
+    };
+    options.insert("flags", flags.bits() as i32);
+    options.insert("refs", revisions);
+    let policy = source.policy(config.verification)?;
+    require_signatures(repo, source, policy, &options)?;
+
+    info!("Pulling {:?} from {}", revisions, &source.name);
+    repo.pull_with_options(&source.name, &options.to_variant(), progress, cancellable)
+        .map_err(|error| match error.matches(IOErrorEnum::Cancelled) {
+            true => Error::Cancelled,
+            false => Error::GLib(error),
+        })?;
+    for revision in revisions {
+        verify(repo, source.keys(), policy, revision, cancellable)?;
+    }
+    Ok(())
+}
+
 pub fn pull(
     repo: &Repo,
+    config: &Config,
     state: &State,
-    remote: Option<&str>,
+    source: &Source,
     dry_run: bool,
     progress: Option<&AsyncProgress>,
     cancellable: Option<&Cancellable>,
-) -> Result<(bool, String, State), Error> {
+) -> Result<(bool, Changelog, State), Error> {
     let mut refs: Vec<String> = Vec::new();
-    let (origin_remote, origin_refspec) = ostree::parse_refspec(&state.core.refspec)?;
-    let remote = match remote {
-        Some(remote) => remote.to_string(),
-        None => match origin_remote {
-            Some(remote) => remote.to_string(),
-            None => "rlxos".to_string(),
-        },
-    };
+    let (_, origin_refspec) = ostree::parse_refspec(&state.core.refspec)?;
     refs.push(origin_refspec.to_string());
 
     for ext in state.extensions.iter() {
@@ -35,43 +133,101 @@ pub fn pull(
     let mut pull_flags = RepoPullFlags::NONE;
     if dry_run {
         pull_flags |= RepoPullFlags::COMMIT_ONLY;
+        options.insert("depth", &(changelog::MAX_COMMITS as i32));
     }
 
     options.insert("flags", &(pull_flags.bits() as i32));
     options.insert("refs", &&refs[..]);
 
-    info!("Pulling {:?} from {}", refs, &remote);
-    repo.pull_with_options(&remote, &options.to_variant(), progress, cancellable)?;
+    let policy = source.policy(config.verification)?;
+    require_signatures(repo, source, policy, &options)?;
+
+    let deltas = match dry_run {
+        true => DeltaPolicy::Auto,
+        false => config.deltas,
+    };
+    match deltas {
+        DeltaPolicy::Auto => {}
+        DeltaPolicy::Force | DeltaPolicy::Fallback => options.insert("require-static-deltas", true),
+        DeltaPolicy::Disable => options.insert("disable-static-deltas", true),
+    }
+
+    // Delta usage is only known from the progress counters.
+    let counters = AsyncProgress::new();
+    let counters = progress.unwrap_or(&counters);
+
+    info!(
+        "Pulling {:?} from {} (deltas: {:?})",
+        refs, &source.name, deltas
+    );
+    let mut fallback = false;
+    let mut pulled = repo.pull_with_options(
+        &source.name,
+        &options.to_variant(),
+        Some(counters),
+        cancellable,
+    );
+    if let Err(error) = &pulled {
+        if deltas == DeltaPolicy::Fallback && !error.matches(IOErrorEnum::Cancelled) {
+            warn!("Pull with static deltas failed, pulling objects: {}", error);
+            fallback = true;
+            options.remove("require-static-deltas");
+            options.insert("disable-static-deltas", true);
+            pulled = repo.pull_with_options(
+                &source.name,
+                &options.to_variant(),
+                Some(counters),
+                cancellable,
+            );
+        }
+    }
+    pulled.map_err(|error| match error.matches(IOErrorEnum::Cancelled) {
+        true => Error::Cancelled,
+        false => Error::GLib(error),
+    })?;
     info!("Pull success");
 
+    // Check what was pulled before the origin refs move to it.
+    for refspec in &refs {
+        let pulled = format!("{}:{}", source.name, refspec);
+        if let Some(revision) = repo.resolve_rev(&pulled, true)? {
+            verify(repo, source.keys(), policy, &revision, cancellable)?;
+        }
+    }
+    source.mirror(&refs, cancellable)?;
+
     if let Some(progress) = progress {
         progress.finish();
-        println!("\n");
+        if crate::progress::rendered(progress) {
+            println!("\n");
+        }
     }
 
     let mut changed = false;
-    let mut changelog = String::new();
+    let mut changelog = Changelog::default();
     let mut changed_core = RefState {
         refspec: state.core.refspec.clone(),
         revision: state.core.revision.clone(),
     };
 
-    let (core_updated, core_revision, core_changelog) =
-        get_changelog(&repo, &state.core.refspec, &state.core.revision)?;
-    if core_updated {
+    if let Some(core_update) =
+        ChangelogEntry::for_ref(repo, &state.core.refspec, &state.core.revision)?
+    {
         changed = true;
-        changelog.push_str(&core_changelog);
-        changed_core.revision = core_revision;
+        changed_core.revision = core_update.new_revision.clone();
+        changelog.push(core_update);
     }
 
     let mut changed_extensions: Vec<RefState> = Vec::new();
 
     for extension in &state.extensions {
-        let (extension_updated, extension_revision, extension_changelog) =
-            get_changelog(&repo, &extension.refspec, &extension.revision)?;
-        if extension_updated {
+        let mut extension_revision = extension.revision.clone();
+        if let Some(extension_update) =
+            ChangelogEntry::for_ref(repo, &extension.refspec, &extension.revision)?
+        {
             changed = true;
-            changelog.push_str(format!("\n{}", extension_changelog).as_str());
+            extension_revision = extension_update.new_revision.clone();
+            changelog.push(extension_update);
         }
         changed_extensions.push(RefState {
             refspec: extension.refspec.clone(),
@@ -79,7 +235,14 @@ pub fn pull(
         });
     }
 
-    
+    if !dry_run {
+        let transfer = transfer(repo, counters, fallback, &changelog, cancellable)?;
+        info!("Pull summary: {}", transfer);
+        if progress.is_some_and(crate::progress::rendered) && !changelog.entries.is_empty() {
+            println!("{}", transfer);
+        }
+    }
+
     Ok((
         changed,
         changelog,
@@ -92,42 +255,102 @@ pub fn pull(
     ))
 }
 
-fn get_changelog(
+/// Have the pull itself reject commits lacking the signatures `policy`
+/// requires, so refs never move to unverified commits. GPG verification can
+/// be forced per pull, ed25519 verification only happens with the keys
+/// configured on the remote, so a remote without them is refused.
+fn require_signatures(
     repo: &Repo,
-    refspec: &str,
-    old_revision: &str,
-) -> Result<(bool, String, String), Error> {
-    info!("Getting changelog for {}", refspec);
-    let updated_revision = match repo.resolve_rev(refspec, false)? {
-        Some(revision) => revision,
-        None => return Err(Error::NoRevisionForRefSpec(refspec.into())),
+    source: &Source,
+    policy: Verification,
+    options: &VariantDict,
+) -> Result<(), Error> {
+    let remote = remote::get(repo, &source.name)?;
+    let missing = |method: &str| {
+        Error::VerificationFailed(
+            source.name.clone(),
+            format!("no {} verification configured for the remote", method),
+        )
     };
-    if updated_revision == old_revision {
-        return Ok((false, old_revision.to_string(), "".into()));
+    match policy {
+        Verification::Remote => {}
+        Verification::Gpg => options.insert("gpg-verify", true),
+        Verification::Sign if !remote.sign_verify => return Err(missing("ed25519")),
+        Verification::Sign => {}
+        Verification::Any if !remote.gpg_verify && !remote.sign_verify => {
+            return Err(missing("signature"))
+        }
+        Verification::Any => {}
     }
+    Ok(())
+}
 
-    info!("Updated revision {}", updated_revision);
+/// Summarize how the commits of `changelog` were fetched, from the counters
+/// of the pull that just finished.
+fn transfer(
+    repo: &Repo,
+    counters: &AsyncProgress,
+    fallback: bool,
+    changelog: &Changelog,
+    cancellable: Option<&Cancellable>,
+) -> Result<Transfer, Error> {
+    let delta = !fallback && counters.uint("total-delta-parts") > 0;
+    let mut objects_size = None;
+    if delta {
+        objects_size = Some(0);
+        for entry in &changelog.entries {
+            let size = estimate::object_pull_size(
+                repo,
+                &entry.old_revision,
+                &entry.new_revision,
+                cancellable,
+            )?;
+            objects_size = objects_size.zip(size).map(|(total, size)| total + size);
+        }
+    }
+    Ok(Transfer {
+        delta,
+        fallback,
+        transferred: counters.uint64("bytes-transferred"),
+        objects_size,
+    })
+}
 
-    let commit = repo.load_variant(ostree::ObjectType::Commit, &updated_revision)?;
-    let subject = commit
-        .child_value(3)
-        .get::<String>()
-        .unwrap_or_else(|| "".into());
-    let body = commit
-        .child_value(4)
-        .get::<String>()
-        .unwrap_or_else(|| "".into());
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok((
-        true,
-        updated_revision.to_string(),
-        format!(
-            "{}: {}\n{}\nrev: {} -> {}\n",
-            refspec,
-            subject.trim(),
-            body.trim(),
-            old_revision,
-            updated_revision
-        ),
-    ))
+    #[test]
+    fn saved_by_deltas() {
+        let transfer = Transfer {
+            delta: true,
+            fallback: false,
+            transferred: 300,
+            objects_size: Some(1000),
+        };
+        assert_eq!(transfer.saved(), Some(700));
+
+        let larger = Transfer {
+            transferred: 1200,
+            ..transfer
+        };
+        assert_eq!(larger.saved(), Some(0));
+
+        let unknown = Transfer {
+            objects_size: None,
+            ..transfer
+        };
+        assert_eq!(unknown.saved(), None);
+    }
+
+    #[test]
+    fn nothing_saved_without_deltas() {
+        let transfer = Transfer {
+            delta: false,
+            fallback: true,
+            transferred: 300,
+            objects_size: Some(1000),
+        };
+        assert_eq!(transfer.saved(), None);
+    }
 }
@@ -0,0 +1,435 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::{process, ptr};
+
+use ostree::gio::Cancellable;
+use ostree::glib::translate::{from_glib_full, ToGlibPtr};
+use ostree::glib::{KeyFile, KeyFileFlags, ToVariant, VariantDict};
+use ostree::prelude::*;
+use ostree::{ffi, gio, glib, Repo};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::config::Verification;
+use crate::Error;
+
+/// A remote as configured in the system repository.
+#[derive(Debug, Clone, Serialize)]
+pub struct Remote {
+    pub name: String,
+    pub url: String,
+    pub contenturl: Option<String>,
+    pub gpg_verify: bool,
+    pub gpg_verify_summary: bool,
+    pub sign_verify: bool,
+    pub branches: Vec<String>,
+}
+
+/// Options for a new remote.
+#[derive(Debug, Clone)]
+pub struct RemoteOptions {
+    pub contenturl: Option<String>,
+    pub gpg_verify: bool,
+    pub branches: Vec<String>,
+    /// Additional `key=value` options written to the remote configuration.
+    pub options: Vec<(String, String)>,
+    /// Files holding GPG keys to import into the remote keyring.
+    pub gpg_keys: Vec<PathBuf>,
+}
+
+impl Default for RemoteOptions {
+    fn default() -> Self {
+        RemoteOptions {
+            contenturl: None,
+            gpg_verify: true,
+            branches: Vec::new(),
+            options: Vec::new(),
+            gpg_keys: Vec::new(),
+        }
+    }
+}
+
+/// Changes to an existing remote, unset fields are left as they are.
+#[derive(Debug, Clone, Default)]
+pub struct RemoteChanges {
+    pub contenturl: Option<String>,
+    pub gpg_verify: Option<bool>,
+    pub branches: Option<Vec<String>>,
+    /// Additional `key=value` options written to the remote configuration.
+    pub options: Vec<(String, String)>,
+    /// Files holding GPG keys to import into the remote keyring.
+    pub gpg_keys: Vec<PathBuf>,
+}
+
+/// Remote a pull reads from. Pulled refs are always recorded under `origin`,
+/// so pulling through a mirror or a one-off url leaves deployments tracking
+/// their configured remote.
+pub struct Source {
+    pub name: String,
+    pub origin: String,
+    repo: Repo,
+    temporary: bool,
+}
+
+impl Source {
+    /// Resolve `remote`, which may be a remote name or a url. A url is added
+    /// as a temporary remote that is deleted again once the source is
+    /// dropped. It verifies commits like `origin`, with the same keys.
+    pub fn new(repo: &Repo, origin: &str, remote: Option<&str>) -> Result<Source, Error> {
+        let mut source = Source {
+            name: origin.to_string(),
+            origin: origin.to_string(),
+            repo: repo.clone(),
+            temporary: false,
+        };
+        match remote {
+            Some(url) if url.contains("://") => {
+                let name = format!("updates-{}", process::id());
+                let options = verification_options(repo, origin)?;
+
+                info!("Adding temporary remote {} for {}", name, url);
+                repo.remote_add(
+                    &name,
+                    Some(url),
+                    Some(&options.to_variant()),
+                    Cancellable::NONE,
+                )?;
+                source.name = name;
+                source.temporary = true;
+            }
+            Some(name) => {
+                exists(repo, name)?;
+                source.name = name.to_string();
+            }
+            None => {}
+        }
+        Ok(source)
+    }
+
+    /// Remote whose keys verify commits pulled from this source. A temporary
+    /// remote has no keyring of its own and borrows that of the origin.
+    pub fn keys(&self) -> &str {
+        match self.temporary {
+            true => &self.origin,
+            false => &self.name,
+        }
+    }
+
+    /// Verification applied to commits pulled from this source. A temporary
+    /// remote doesn't verify anything itself, so following the remote
+    /// configuration means following that of the origin.
+    pub fn policy(&self, policy: Verification) -> Result<Verification, Error> {
+        match self.temporary {
+            true => origin_policy(&self.repo, &self.origin, policy),
+            false => Ok(policy),
+        }
+    }
+
+    /// Record `refs` pulled from this source under the origin remote, once
+    /// their commits are verified.
+    pub fn mirror(&self, refs: &[String], cancellable: Option<&Cancellable>) -> Result<(), Error> {
+        if self.name == self.origin {
+            return Ok(());
+        }
+        for refspec in refs {
+            let pulled = format!("{}:{}", self.name, refspec);
+            let Some(revision) = self.repo.resolve_rev(&pulled, true)? else {
+                continue;
+            };
+            self.repo.set_ref_immediate(
+                Some(&self.origin),
+                refspec,
+                Some(&revision),
+                cancellable,
+            )?;
+            if self.temporary {
+                self.repo
+                    .set_ref_immediate(Some(&self.name), refspec, None, cancellable)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Source {
+    fn drop(&mut self) {
+        if !self.temporary {
+            return;
+        }
+        info!("Deleting temporary remote {}", self.name);
+        if let Err(error) = self.repo.remote_delete(&self.name, Cancellable::NONE) {
+            warn!("failed to delete temporary remote {}: {}", self.name, error);
+        }
+    }
+}
+
+/// Options making a temporary remote verify commits the way `origin` does:
+/// the same methods, ed25519 keys and GPG keyrings. Its summary is left
+/// unverified, commit signatures are what protects the refs.
+fn verification_options(repo: &Repo, origin: &str) -> Result<VariantDict, Error> {
+    let remote = get(repo, origin)?;
+    let options = VariantDict::new(None);
+    options.insert("gpg-verify", remote.gpg_verify);
+    options.insert("gpg-verify-summary", false);
+    options.insert("sign-verify", remote.sign_verify);
+    for key in ["verification-ed25519-key", "verification-ed25519-file"] {
+        if let Some(value) = repo.remote_option(origin, key, None)? {
+            options.insert(key, value.as_str());
+        }
+    }
+
+    let mut keyrings: Vec<String> = Vec::new();
+    if let Some(path) = repo.remote_option(origin, "gpgkeypath", None)? {
+        keyrings.push(path.to_string());
+    }
+    if let Some(path) = repo.path().path() {
+        let keyring = path.join(format!("{}.trustedkeys.gpg", origin));
+        if keyring.exists() {
+            keyrings.push(keyring.display().to_string());
+        }
+    }
+    if !keyrings.is_empty() {
+        options.insert("gpgkeypath", keyrings.join(";").as_str());
+    }
+    Ok(options)
+}
+
+/// Verification for commits of `origin` obtained without going through it,
+/// where the remote configuration can't do the checks itself: `Remote` is
+/// turned into the methods enabled on `origin`.
+pub fn origin_policy(
+    repo: &Repo,
+    origin: &str,
+    policy: Verification,
+) -> Result<Verification, Error> {
+    if policy != Verification::Remote {
+        return Ok(policy);
+    }
+    let origin = get(repo, origin)?;
+    Ok(match (origin.gpg_verify, origin.sign_verify) {
+        (true, true) => Verification::Any,
+        (true, false) => Verification::Gpg,
+        (false, true) => Verification::Sign,
+        (false, false) => Verification::Remote,
+    })
+}
+
+/// Url for pulling from the repository at `path`, a local archive repo such
+/// as one on removable media.
+pub fn local_url(path: &Path) -> Result<String, Error> {
+    let path = path
+        .canonicalize()
+        .map_err(|_| Error::InvalidRepository(path.display().to_string()))?;
+    if !path.join("config").is_file() || !path.join("objects").is_dir() {
+        return Err(Error::InvalidRepository(path.display().to_string()));
+    }
+    Ok(format!("file://{}", path.display()))
+}
+
+pub fn list(repo: &Repo) -> Result<Vec<Remote>, Error> {
+    let mut remotes: Vec<Remote> = Vec::new();
+    for name in repo.remote_list() {
+        remotes.push(get(repo, &name)?);
+    }
+    Ok(remotes)
+}
+
+pub fn get(repo: &Repo, name: &str) -> Result<Remote, Error> {
+    exists(repo, name)?;
+    Ok(Remote {
+        name: name.to_string(),
+        url: repo.remote_get_url(name)?.to_string(),
+        contenturl: repo
+            .remote_option(name, "contenturl", None)?
+            .map(|url| url.to_string()),
+        gpg_verify: repo.remote_get_gpg_verify(name)?,
+        gpg_verify_summary: repo.remote_get_gpg_verify_summary(name)?,
+        sign_verify: repo.remote_boolean_option(name, "sign-verify", false)?,
+        branches: repo
+            .remote_list_option(name, "branches")?
+            .into_iter()
+            .map(|branch| branch.to_string())
+            .collect(),
+    })
+}
+
+/// Add remote `name` and import its GPG keys. Returns the number of keys
+/// imported.
+pub fn add(
+    repo: &Repo,
+    name: &str,
+    url: &str,
+    options: &RemoteOptions,
+    cancellable: Option<&Cancellable>,
+) -> Result<u32, Error> {
+    let dict = VariantDict::new(None);
+    dict.insert("gpg-verify", options.gpg_verify);
+    if let Some(contenturl) = &options.contenturl {
+        dict.insert("contenturl", contenturl);
+    }
+    if !options.branches.is_empty() {
+        dict.insert("branches", &options.branches);
+    }
+    for (key, value) in &options.options {
+        dict.insert(key, value);
+    }
+
+    info!("Adding remote {} at {}", name, url);
+    repo.remote_add(name, Some(url), Some(&dict.to_variant()), cancellable)?;
+
+    match import_keys(repo, name, &options.gpg_keys, cancellable) {
+        Ok(imported) => Ok(imported),
+        Err(error) => {
+            warn!("Deleting remote {} after failed key import", name);
+            let _ = repo.remote_delete(name, cancellable);
+            Err(error)
+        }
+    }
+}
+
+/// Apply `changes` to remote `name` and import its new GPG keys. Returns the
+/// number of keys imported.
+pub fn modify(
+    repo: &Repo,
+    name: &str,
+    changes: &RemoteChanges,
+    cancellable: Option<&Cancellable>,
+) -> Result<u32, Error> {
+    info!("Modifying remote {}", name);
+    edit(repo, name, cancellable, |keyfile, group| {
+        if let Some(contenturl) = &changes.contenturl {
+            keyfile.set_string(group, "contenturl", contenturl);
+        }
+        if let Some(gpg_verify) = changes.gpg_verify {
+            keyfile.set_boolean(group, "gpg-verify", gpg_verify);
+        }
+        if let Some(branches) = &changes.branches {
+            // GKeyFile string list syntax, branch names hold no separators.
+            let branches = branches
+                .iter()
+                .map(|b| format!("{};", b))
+                .collect::<String>();
+            keyfile.set_value(group, "branches", &branches);
+        }
+        for (key, value) in &changes.options {
+            keyfile.set_string(group, key, value);
+        }
+    })?;
+    import_keys(repo, name, &changes.gpg_keys, cancellable)
+}
+
+pub fn delete(repo: &Repo, name: &str, cancellable: Option<&Cancellable>) -> Result<(), Error> {
+    exists(repo, name)?;
+    info!("Deleting remote {}", name);
+    repo.remote_delete(name, cancellable)?;
+    Ok(())
+}
+
+/// Point remote `name` at `url`, keeping the rest of its configuration.
+pub fn set_url(
+    repo: &Repo,
+    name: &str,
+    url: &str,
+    cancellable: Option<&Cancellable>,
+) -> Result<(), Error> {
+    info!("Setting url of remote {} to {}", name, url);
+    edit(repo, name, cancellable, |keyfile, group| {
+        keyfile.set_string(group, "url", url)
+    })
+}
+
+/// Apply `change` to the configuration group of remote `name`. The remote
+/// is updated in place, whether it lives in the repository config or in a
+/// file of the remotes config directory.
+fn edit<F>(
+    repo: &Repo,
+    name: &str,
+    cancellable: Option<&Cancellable>,
+    change: F,
+) -> Result<(), Error>
+where
+    F: Fn(&KeyFile, &str),
+{
+    exists(repo, name)?;
+    let group = format!("remote \"{}\"", name);
+
+    let config = repo.copy_config();
+    if config.has_group(&group) {
+        change(&config, &group);
+        repo.write_config(&config)?;
+        repo.reload_config(cancellable)?;
+        return Ok(());
+    }
+
+    if let Some(dir) = repo.remotes_config_dir() {
+        for entry in fs::read_dir(dir.as_str())? {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new("conf")) {
+                continue;
+            }
+            let keyfile = KeyFile::new();
+            keyfile.load_from_file(&path, KeyFileFlags::KEEP_COMMENTS)?;
+            if keyfile.has_group(&group) {
+                change(&keyfile, &group);
+                keyfile.save_to_file(&path)?;
+                repo.reload_config(cancellable)?;
+                return Ok(());
+            }
+        }
+    }
+
+    Err(Error::RemoteNotFound(name.to_string()))
+}
+
+fn import_keys(
+    repo: &Repo,
+    name: &str,
+    paths: &[PathBuf],
+    cancellable: Option<&Cancellable>,
+) -> Result<u32, Error> {
+    let mut imported = 0;
+    for path in paths {
+        let stream = gio::File::for_path(path).read(cancellable)?;
+        imported += gpg_import(repo, name, &stream, cancellable)?;
+    }
+    Ok(imported)
+}
+
+fn exists(repo: &Repo, name: &str) -> Result<(), Error> {
+    match repo.remote_list().iter().any(|remote| remote == name) {
+        true => Ok(()),
+        false => Err(Error::RemoteNotFound(name.to_string())),
+    }
+}
+
+/// `Repo::remote_gpg_import` passes an empty key id list instead of NULL,
+/// which imports nothing, so call into libostree directly to import every
+/// key of `stream`.
+fn gpg_import(
+    repo: &Repo,
+    name: &str,
+    stream: &impl IsA<gio::InputStream>,
+    cancellable: Option<&Cancellable>,
+) -> Result<u32, glib::Error> {
+    unsafe {
+        let mut imported = 0;
+        let mut error = ptr::null_mut();
+        let is_ok = ffi::ostree_repo_remote_gpg_import(
+            repo.to_glib_none().0,
+            name.to_glib_none().0,
+            stream.as_ref().to_glib_none().0,
+            ptr::null(),
+            &mut imported,
+            cancellable.to_glib_none().0,
+            &mut error,
+        );
+        assert_eq!(is_ok == glib::ffi::GFALSE, !error.is_null());
+
+        if error.is_null() {
+            Ok(imported)
+        } else {
+            Err(from_glib_full(error))
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::{HashMap, VecDeque};
+
+use ostree::gio::Cancellable;
+use ostree::glib::VariantDict;
+use ostree::{ObjectType, Repo};
+use tracing::info;
+
+use crate::config::Config;
+use crate::engine::extension::Extension;
+use crate::engine::pull::pull_commit;
+use crate::engine::remote::Source;
+use crate::engine::state::State;
+use crate::Error;
+
+/// Extensions an extension depends on and those it can't be installed with,
+/// from the `requires` and `conflicts` metadata of its commit.
+#[derive(Debug, Clone, Default)]
+pub struct Requirements {
+    pub requires: Vec<String>,
+    pub conflicts: Vec<String>,
+}
+
+/// Compute the final extension set of `state`. Duplicates and `excluded`
+/// extensions are dropped, dependencies are added unless excluded, and
+/// conflicting extensions are refused. Returns warnings about dependencies
+/// left out because they were excluded.
+pub fn resolve<F>(
+    config: &Config,
+    state: &mut State,
+    excluded: &[String],
+    mut requirements: F,
+) -> Result<Vec<String>, Error>
+where
+    F: FnMut(&str) -> Result<Requirements, Error>,
+{
+    let mut ids: Vec<String> = Vec::new();
+    state.extensions.retain(|extension| {
+        let id = config
+            .extension_id(&extension.refspec)
+            .unwrap_or_else(|| extension.refspec.clone());
+        if excluded.contains(&id) || ids.contains(&id) {
+            return false;
+        }
+        ids.push(id);
+        true
+    });
+
+    let mut warnings: Vec<String> = Vec::new();
+    let mut resolved: HashMap<String, Requirements> = HashMap::new();
+    let mut queue: VecDeque<String> = ids.iter().cloned().collect();
+    while let Some(id) = queue.pop_front() {
+        let requirements = requirements(&id)?;
+        for dependency in &requirements.requires {
+            if excluded.contains(dependency) {
+                warnings.push(format!("{} requires excluded extension {}", id, dependency));
+            } else if !ids.contains(dependency) {
+                info!("Adding {} required by {}", dependency, id);
+                state.add_extension(config, dependency);
+                ids.push(dependency.clone());
+                queue.push_back(dependency.clone());
+            }
+        }
+        resolved.insert(id, requirements);
+    }
+
+    for (id, requirements) in &resolved {
+        if let Some(conflict) = requirements.conflicts.iter().find(|c| ids.contains(c)) {
+            return Err(Error::ExtensionConflict(id.clone(), conflict.clone()));
+        }
+    }
+
+    Ok(warnings)
+}
+
+/// Requirements of `extension`, fetching its commit when the catalog entry
+/// was built without it. No ref moves, so this is safe for checks.
+pub fn requirements(
+    repo: &Repo,
+    config: &Config,
+    source: &Source,
+    extension: &Extension,
+    cancellable: Option<&Cancellable>,
+) -> Result<Requirements, Error> {
+    if repo.has_object(ObjectType::Commit, &extension.revision, cancellable)? {
+        return Ok(Requirements {
+            requires: extension.requires.clone(),
+            conflicts: extension.conflicts.clone(),
+        });
+    }
+
+    pull_commit(repo, config, source, &extension.revision, cancellable)?;
+
+    let commit = repo.load_variant(ObjectType::Commit, &extension.revision)?;
+    let metadata = VariantDict::new(Some(&commit.child_value(0)));
+    let list = |name: &str| {
+        metadata
+            .lookup::<Vec<String>>(&config.metadata_key(name))
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+    };
+
+    Ok(Requirements {
+        requires: list("requires"),
+        conflicts: list("conflicts"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::state::RefState;
+
+    fn state(config: &Config, extensions: &[&str]) -> State {
+        let mut state = State {
+            revision: String::new(),
+            core: RefState {
+                refspec: format!("{}:{}", config.remote, config.core_ref("stable")),
+                revision: String::new(),
+            },
+            merged: true,
+            extensions: Vec::new(),
+        };
+        for id in extensions {
+            state.add_extension(config, id);
+        }
+        state
+    }
+
+    fn ids(config: &Config, state: &State) -> Vec<String> {
+        state
+            .extensions
+            .iter()
+            .map(|extension| config.extension_id(&extension.refspec).unwrap())
+            .collect()
+    }
+
+    /// Requirements from a table of `(id, requires, conflicts)`, extensions
+    /// missing from it have none.
+    fn table<'a>(
+        entries: &'a [(&str, &[&str], &[&str])],
+    ) -> impl FnMut(&str) -> Result<Requirements, Error> + 'a {
+        move |id| {
+            let list = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect();
+            Ok(entries
+                .iter()
+                .find(|(entry, _, _)| *entry == id)
+                .map(|(_, requires, conflicts)| Requirements {
+                    requires: list(requires),
+                    conflicts: list(conflicts),
+                })
+                .unwrap_or_default())
+        }
+    }
+
+    #[test]
+    fn resolve_adds_transitive_dependencies() {
+        let config = Config::default();
+        let mut state = state(&config, &["devel"]);
+        let requirements = table(&[("devel", &["sdk"], &[]), ("sdk", &["debug"], &[])]);
+        let warnings = resolve(&config, &mut state, &[], requirements).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(ids(&config, &state), ["devel", "sdk", "debug"]);
+    }
+
+    #[test]
+    fn resolve_stops_on_cycles() {
+        let config = Config::default();
+        let mut state = state(&config, &["devel"]);
+        let mut calls = 0;
+        let mut requirements = table(&[("devel", &["sdk"], &[]), ("sdk", &["devel"], &[])]);
+        let counted = |id: &str| {
+            calls += 1;
+            requirements(id)
+        };
+        resolve(&config, &mut state, &[], counted).unwrap();
+        assert_eq!(ids(&config, &state), ["devel", "sdk"]);
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn resolve_leaves_out_excluded_dependencies() {
+        let config = Config::default();
+        let mut state = state(&config, &["devel", "debug"]);
+        let requirements = table(&[("devel", &["debug"], &[])]);
+        let excluded = ["debug".to_string()];
+        let warnings = resolve(&config, &mut state, &excluded, requirements).unwrap();
+        assert_eq!(ids(&config, &state), ["devel"]);
+        assert_eq!(warnings, ["devel requires excluded extension debug"]);
+    }
+
+    #[test]
+    fn resolve_refuses_conflicts() {
+        let config = Config::default();
+        let mut state = state(&config, &["devel", "nvidia"]);
+        let requirements = table(&[("devel", &["sdk"], &[]), ("nvidia", &[], &["sdk"])]);
+        match resolve(&config, &mut state, &[], requirements) {
+            Err(Error::ExtensionConflict(id, conflict)) => {
+                assert_eq!((id.as_str(), conflict.as_str()), ("nvidia", "sdk"));
+            }
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+    }
+}
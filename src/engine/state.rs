@@ -1,44 +1,19 @@
-use std::env;
+use std::collections::BTreeMap;
 
-use ostree::glib::{GString, VariantDict, VariantTy};
+use ostree::glib::{GString, ToVariant, Variant, VariantDict, VariantTy};
 use ostree::{Deployment, ObjectType, Repo};
+use serde::{Deserialize, Serialize};
+use tracing::info;
 
-use crate::{engine::RefData, Error};
+use crate::{config::Config, Error};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RefState {
     pub refspec: String,
     pub revision: String,
 }
 
-impl RefState {
-    pub fn get_data(&self, t: RefData) -> String {
-        let data = self
-            .refspec
-            .split(':')
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
-        let (remote, remaining) = match data.len() {
-            2 => (data[0].clone(), data[1].clone()),
-            _ => ("".to_string(), data[0].clone()),
-        };
-
-        let data = remaining
-            .split('/')
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
-
-        match t {
-            RefData::Remote => remote,
-            RefData::Arch => data[0].clone(),
-            RefData::Type => data[1].clone(),
-            RefData::Id => data[data.len() - 1].clone(),
-            RefData::Channel => data.last().unwrap().to_string(),
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct State {
     pub revision: String,
     pub core: RefState,
@@ -47,84 +22,97 @@ pub struct State {
 }
 
 impl State {
-    pub fn options(&self) -> (VariantDict, String) {
-        let options = VariantDict::new(None);
+    /// Revisions of the inputs keyed by their metadata key, sorted so equal
+    /// states give the same merged commit metadata, along with the extension
+    /// list for the origin file.
+    pub fn options(&self, config: &Config) -> (BTreeMap<String, Variant>, String) {
+        let mut options = BTreeMap::new();
         let mut extensions_string: String = "".to_string();
-        options.insert("rlxos.revision.core", &self.core.revision);
+        options.insert(config.revision_key("core"), self.core.revision.to_variant());
         if self.merged {
             for extension in self.extensions.iter() {
-                let extension_id = extension
-                    .refspec
-                    .to_string()
-                    .split("/")
-                    .map(|s| s.to_string())
-                    .collect::<Vec<String>>()[2]
-                    .clone();
+                let extension_id = config
+                    .extension_id(&extension.refspec)
+                    .unwrap_or_else(|| extension.refspec.clone());
                 extensions_string.push_str(&format!("{extension_id};"));
                 options.insert(
-                    &format!("rlxos.revision.{}", extension_id),
-                    &extension.revision,
+                    config.revision_key(&extension_id),
+                    extension.revision.to_variant(),
                 );
             }
         }
         (options, extensions_string)
     }
 
-    pub fn channel(&self) -> String {
-        self.core
-            .refspec
-            .to_string()
-            .split("/")
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>()
-            .last()
-            .unwrap()
-            .to_string()
+    /// Channel of the core ref, read with the configured template. Refs that
+    /// don't follow it are assumed to end with the channel.
+    pub fn channel(&self, config: &Config) -> String {
+        match config.core_channel(&self.core.refspec) {
+            Some(channel) => channel,
+            None => last_segment(&self.core.refspec).to_string(),
+        }
     }
 
-    pub fn add_extension(&mut self, extension: &str) {
-        let extension = match extension.contains("/extension/") {
+    /// Remote of the core ref, the configured one when it names none.
+    pub fn remote<'a>(&'a self, config: &'a Config) -> &'a str {
+        match self.core.refspec.split_once(':') {
+            Some((remote, _)) => remote,
+            None => &config.remote,
+        }
+    }
+
+    /// Add `extension`, a refspec or an id tracked on the channel and remote
+    /// of the core ref.
+    pub fn add_extension(&mut self, config: &Config, extension: &str) {
+        let extension = match config.is_extension_ref(extension) {
             true => extension.to_string(),
             false => format!(
-                "rlxos:{}/extension/{}/{}",
-                env::consts::ARCH,
-                extension,
-                self.channel()
+                "{}:{}",
+                self.remote(config),
+                config.extension_ref(extension, &self.channel(config))
             ),
         };
 
-        // info!("formated extension: {}", extension);
-        // if !self
-        //     .extensions
-        //     .iter()
-        //     .filter(|v| v.refspec == extension)
-        //     .count() == 0
-        // {
-        //     info!("Setting extension: {}", extension);
-
-        // }
+        if self.extensions.iter().any(|v| v.refspec == extension) {
+            info!("Extension {} already included", extension);
+            return;
+        }
         self.extensions.push(RefState {
             refspec: extension.clone(),
             revision: "".into(),
         })
     }
 
-    pub fn switch_channel(&mut self, channel: &str) {
-        let old_channel = self.channel();
-        println!("{} -> {}", old_channel, channel);
-        self.core.refspec = self.core.refspec.replace(&old_channel, channel);
+    /// Point the core and extension refs at `channel`, rebuilding them from
+    /// the configured templates.
+    pub fn switch_channel(&mut self, config: &Config, channel: &str) {
+        let old_channel = self.channel(config);
+        info!("Switching channel {} -> {}", old_channel, channel);
+        self.core.refspec = match config.core_channel(&self.core.refspec) {
+            Some(_) => with_remote(&self.core.refspec, &config.core_ref(channel)),
+            None => with_last_segment(&self.core.refspec, channel),
+        };
         self.core.revision = "".to_string();
 
         for extension in self.extensions.iter_mut() {
-            extension.refspec = extension.refspec.replace(&old_channel, channel);
+            extension.refspec = match config.extension_id(&extension.refspec) {
+                Some(id) => with_remote(&extension.refspec, &config.extension_ref(&id, channel)),
+                None => with_last_segment(&extension.refspec, channel),
+            };
             extension.revision = "".to_string();
         }
     }
-    pub fn for_deployment(repo: &Repo, deployment: &Deployment) -> Result<State, Error> {
+
+    pub fn for_deployment(
+        repo: &Repo,
+        deployment: &Deployment,
+        config: &Config,
+    ) -> Result<State, Error> {
         let origin = deployment.origin().unwrap();
+        let group = config.origin_group();
         let refspec = origin.string("origin", "refspec")?.to_string();
         let revision = deployment.csum().to_string();
-        let merged = origin.boolean("rlxos", "merged").unwrap_or_else(|_| false);
+        let merged = origin.boolean(group, "merged").unwrap_or_else(|_| false);
 
         if !merged {
             return Ok(State {
@@ -136,24 +124,25 @@ impl State {
         }
 
         let channel = origin
-            .string("rlxos", "channel")
-            .unwrap_or_else(|_| "stable".into())
-            .to_string();
-
-        let refspec = format!(
-            "{}:{}/os/{}",
-            deployment.osname(),
-            env::consts::ARCH,
-            channel
-        );
+            .string(group, "channel")
+            .map(|channel| channel.to_string())
+            .unwrap_or_else(|_| config.channel.clone());
+
+        // Deployments written before the remote was recorded track the
+        // remote named after their os.
+        let remote = origin
+            .string(group, "remote")
+            .map(|remote| remote.to_string())
+            .unwrap_or_else(|_| deployment.osname().to_string());
+        let refspec = format!("{}:{}", remote, config.core_ref(&channel));
 
         let commit = repo.load_variant(ObjectType::Commit, &revision)?;
         let commit_metadata = VariantDict::new(Some(&commit.child_value(0)));
 
-        let core_revision = get_revision(&commit_metadata, "core");
+        let core_revision = get_revision(&commit_metadata, &config.revision_key("core"));
 
         let extensions_refspec: Vec<String> = origin
-            .string("rlxos", "extensions")
+            .string(group, "extensions")
             .unwrap_or_else(|_| GString::from(""))
             .to_string()
             .split(";")
@@ -164,18 +153,12 @@ impl State {
             if ext.is_empty() {
                 continue;
             }
-            // Skip previous extensions
-            if ext.contains("/extension/") {
+            // Skip extensions recorded as refspecs by older versions
+            if config.is_extension_ref(&ext) {
                 continue;
             }
-            let ext_refspec = format!(
-                "{}:{}/extension/{}/{}",
-                deployment.osname(),
-                env::consts::ARCH,
-                ext,
-                channel
-            );
-            let ext_revision = get_revision(&commit_metadata, &ext);
+            let ext_refspec = format!("{}:{}", remote, config.extension_ref(&ext, &channel));
+            let ext_revision = get_revision(&commit_metadata, &config.revision_key(&ext));
             extensions.push(RefState {
                 refspec: ext_refspec,
                 revision: ext_revision,
@@ -194,12 +177,76 @@ impl State {
     }
 }
 
-fn get_revision(metadata: &VariantDict, id: &str) -> String {
-    match metadata.lookup_value(
-        format!("rlxos.revision.{}", id).as_str(),
-        Some(&VariantTy::STRING),
-    ) {
+fn get_revision(metadata: &VariantDict, key: &str) -> String {
+    match metadata.lookup_value(key, Some(&VariantTy::STRING)) {
         Some(variant) => variant.get::<String>().unwrap(),
         None => "".into(),
     }
 }
+
+fn last_segment(refspec: &str) -> &str {
+    match refspec.rsplit_once('/') {
+        Some((_, last)) => last,
+        None => refspec,
+    }
+}
+
+fn with_last_segment(refspec: &str, segment: &str) -> String {
+    match refspec.rsplit_once('/') {
+        Some((head, _)) => format!("{}/{}", head, segment),
+        None => segment.to_string(),
+    }
+}
+
+/// `reference` under the remote of `refspec`, if it names one.
+fn with_remote(refspec: &str, reference: &str) -> String {
+    match refspec.split_once(':') {
+        Some((remote, _)) => format!("{}:{}", remote, reference),
+        None => reference.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(refspec: &str) -> State {
+        State {
+            revision: String::new(),
+            core: RefState {
+                refspec: refspec.to_string(),
+                revision: String::new(),
+            },
+            merged: false,
+            extensions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn add_extension_on_core_remote() {
+        let config = Config::default();
+        let mut state = state(&format!("mirror:{}", config.core_ref("beta")));
+        state.add_extension(&config, "devel");
+        state.add_extension(&config, "devel");
+        let refspecs = state
+            .extensions
+            .iter()
+            .map(|extension| extension.refspec.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            refspecs,
+            [format!("mirror:{}", config.extension_ref("devel", "beta"))]
+        );
+    }
+
+    #[test]
+    fn add_extension_on_default_remote() {
+        let config = Config::default();
+        let mut state = state(&config.core_ref("stable"));
+        state.add_extension(&config, "devel");
+        assert_eq!(
+            state.extensions[0].refspec,
+            format!("{}:{}", config.remote, config.extension_ref("devel", "stable"))
+        );
+    }
+}
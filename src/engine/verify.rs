@@ -0,0 +1,74 @@
+use std::fs;
+use std::path::Path;
+
+use ostree::gio::Cancellable;
+use ostree::glib::{Bytes, ToVariant};
+use ostree::prelude::*;
+use ostree::{ObjectType, Repo, RepoVerifyFlags, Sign};
+use tracing::info;
+
+use crate::config::Verification;
+use crate::Error;
+
+/// Check the signatures of `revision` against the keys of `remote` as
+/// required by `policy`.
+pub fn verify(
+    repo: &Repo,
+    remote: &str,
+    policy: Verification,
+    revision: &str,
+    cancellable: Option<&Cancellable>,
+) -> Result<(), Error> {
+    let flags = match policy {
+        Verification::Remote => return Ok(()),
+        Verification::Gpg => RepoVerifyFlags::NO_SIGNAPI,
+        Verification::Sign => RepoVerifyFlags::NO_GPG,
+        Verification::Any => RepoVerifyFlags::NONE,
+    };
+
+    let commit = repo.load_variant(ObjectType::Commit, revision)?;
+    let metadata = match repo.read_commit_detached_metadata(revision, cancellable)? {
+        Some(metadata) => metadata.data_as_bytes(),
+        None => Bytes::from_static(&[]),
+    };
+
+    match repo.signature_verify_commit_data(remote, &commit.data_as_bytes(), &metadata, flags) {
+        Ok(result) => {
+            info!("Verified {}: {}", revision, result);
+            Ok(())
+        }
+        Err(error) => Err(Error::VerificationFailed(
+            revision.to_string(),
+            error.to_string(),
+        )),
+    }
+}
+
+/// Attach an ed25519 signature made with the secret key in `key` to
+/// `revision`. The key file holds the base64 encoded key, as used by
+/// `ostree sign`.
+pub fn sign(
+    repo: &Repo,
+    key: &Path,
+    revision: &str,
+    cancellable: Option<&Cancellable>,
+) -> Result<(), Error> {
+    let contents = fs::read_to_string(key)?;
+    let secret_key = contents
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty());
+    let Some(secret_key) = secret_key else {
+        return Err(Error::InvalidConfig(
+            "verification.signing-key".into(),
+            key.display().to_string(),
+        ));
+    };
+
+    let signer = Sign::by_name("ed25519")?;
+    signer.set_sk(&secret_key.to_variant())?;
+    signer.commit(repo, revision, cancellable)?;
+    info!("Signed {} with {}", revision, key.display());
+
+    Ok(())
+}
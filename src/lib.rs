@@ -1,20 +1,37 @@
 use thiserror::Error;
 
 pub mod cmd;
+pub mod config;
 pub mod engine;
 pub mod progress;
+pub mod server;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("glib")]
     GLib(#[from] ostree::glib::Error),
 
+    #[error("dbus")]
+    DBus(#[from] zbus::Error),
+
+    #[error("io")]
+    Io(#[from] std::io::Error),
+
+    #[error("json")]
+    Json(#[from] serde_json::Error),
+
     #[error("no boot deployment")]
     NoBootDeployment,
 
     #[error("no previous deployment")]
     NoPreviousDeployment,
 
+    #[error("no deployment found for {0}")]
+    NoDeploymentFound(String),
+
+    #[error("deployment {0} is staged, unstage it first")]
+    DeploymentStaged(String),
+
     #[error("no origin known for deployment {0}.{1}")]
     NoOriginForDeployment(String, i32),
 
@@ -42,6 +59,9 @@ pub enum Error {
     #[error("no remote found")]
     NoRemoteFound,
 
+    #[error("remote {0} not found")]
+    RemoteNotFound(String),
+
     #[error("permission denied {0}")]
     PermissionDenied(String),
 
@@ -50,4 +70,41 @@ pub enum Error {
 
     #[error("no updates available")]
     NoUpdateAvailable,
+
+    #[error("extension {0} not found")]
+    ExtensionNotFound(String),
+
+    #[error("extension {0} conflicts with {1}")]
+    ExtensionConflict(String, String),
+
+    #[error("{0} is shipped by both {1} and {2}")]
+    MergeConflict(String, String, String),
+
+    #[error("operation cancelled")]
+    Cancelled,
+
+    #[error("invalid value '{1}' for {0}")]
+    InvalidConfig(String, String),
+
+    #[error("signature verification failed for {0}: {1}")]
+    VerificationFailed(String, String),
+
+    #[error("{0} is not an ostree repository")]
+    InvalidRepository(String),
+
+    #[error("invalid bundle {0}")]
+    InvalidBundle(String),
+}
+
+impl Error {
+    /// Messages of this error and every error that caused it, outermost first.
+    pub fn chain(&self) -> Vec<String> {
+        let mut chain = vec![self.to_string()];
+        let mut source = std::error::Error::source(self);
+        while let Some(error) = source.take() {
+            chain.push(error.to_string());
+            source = error.source();
+        }
+        chain
+    }
 }
@@ -1,117 +1,146 @@
 use humansize::{format_size, DECIMAL};
 use std::io::Write;
 use humantime::format_duration;
+use ostree::glib::FromVariant;
 use ostree::AsyncProgress;
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
+use zbus::zvariant::Type;
+
+/// Key set on progresses rendered to the terminal.
+const RENDERED: &str = "rendered";
 
 pub fn get() -> AsyncProgress {
     let progress = AsyncProgress::new();
+    progress.set_uint(RENDERED, 1);
     progress.connect_changed(crate::progress::update_callback);
 
     progress
 }
 
-pub fn update_callback(p: &AsyncProgress) {
-    let mut message = String::new();
-    let outstanding_fetches = p
-        .variant("outstanding-fetches")
-        .unwrap()
-        .get::<u32>()
-        .unwrap();
-    let outstanding_metadata_fetches = p
-        .variant("outstanding-metadata-fetches")
-        .unwrap()
-        .get::<u32>()
-        .unwrap();
-    let outstanding_writes = p
-        .variant("outstanding-writes")
-        .unwrap()
-        .get::<u32>()
-        .unwrap();
-    let caught_error = p.variant("caught-error").unwrap().get::<bool>().unwrap();
-    let scanning = p.variant("scanning").unwrap().get::<u32>().unwrap();
-    let scanned_metadata = p.variant("scanned-metadata").unwrap().get::<u32>().unwrap();
-    let fetched_delta_parts = p.variant("fetched-delta-parts").unwrap().get::<u32>().unwrap();
-    let total_delta_parts = p.variant("total-delta-parts").unwrap().get::<u32>().unwrap();
-    let fetched_delta_part_fallbacks = p.variant("fetched-delta-fallbacks").unwrap().get::<u32>().unwrap();
-    let total_delta_parts_fallback = p.variant("total-delta-fallbacks").unwrap().get::<u32>().unwrap();
-    let status = p.variant("status").unwrap().get::<String>().unwrap();
+/// Whether `progress` is rendered to the terminal, as opposed to one only
+/// read for its counters.
+pub fn rendered(progress: &AsyncProgress) -> bool {
+    progress.uint(RENDERED) > 0
+}
 
-    // println!("outstanding: {outstanding_fetches}");
-    // println!("total_delta_parts: {total_delta_parts}");
-    if !status.is_empty() {
-        message.push_str(&status);
-    } else if caught_error {
-        message.push_str("caught error, waiting for outstanding tasks");
-    } else if outstanding_fetches > 0 {
-        let current_time = ostree::glib::monotonic_time() as i64;
-        let bytes_transferred = p
-            .variant("bytes-transferred")
-            .unwrap()
-            .get::<u64>()
-            .unwrap();
-        let fetched = p.variant("fetched").unwrap().get::<u32>().unwrap();
-        let metadata_fetched = p.variant("metadata-fetched").unwrap().get::<u32>().unwrap();
-        let requested = p.variant("requested").unwrap().get::<u32>().unwrap();
-        let start_time = p.variant("start-time").unwrap().get::<u64>().unwrap() as i64;
-        let total_delta_part_size = p.variant("total-delta-part-size").unwrap().get::<u64>().unwrap();
-        let formatted_bytes_transferred = format_size(bytes_transferred, DECIMAL);
+/// Snapshot of the pull counters reported by an `AsyncProgress`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Type)]
+pub struct Progress {
+    pub status: String,
+    pub caught_error: bool,
+    pub outstanding_fetches: u32,
+    pub outstanding_metadata_fetches: u32,
+    pub outstanding_writes: u32,
+    pub scanning: u32,
+    pub scanned_metadata: u32,
+    pub fetched: u32,
+    pub metadata_fetched: u32,
+    pub requested: u32,
+    pub fetched_delta_parts: u32,
+    pub total_delta_parts: u32,
+    pub fetched_delta_part_size: u64,
+    pub total_delta_part_size: u64,
+    pub bytes_transferred: u64,
+    pub bytes_sec: u64,
+    pub est_time_remaining: u64,
+}
 
-        let bytes_sec: u64;
-        let formatted_bytes_sec: String;
+impl From<&AsyncProgress> for Progress {
+    fn from(p: &AsyncProgress) -> Self {
+        let fetched_delta_parts =
+            value::<u32>(p, "fetched-delta-parts") + value::<u32>(p, "fetched-delta-fallbacks");
+        let total_delta_parts =
+            value::<u32>(p, "total-delta-parts") + value::<u32>(p, "total-delta-fallbacks");
+        let fetched_delta_part_size = value::<u64>(p, "fetched-delta-part-size");
+        let total_delta_part_size = value::<u64>(p, "total-delta-part-size");
+        let bytes_transferred = value::<u64>(p, "bytes-transferred");
 
-        // println!("TIME: {current_time} - {start_time}: {}", current_time - start_time);
-        // println!("BYTES TRANSFERRED: {bytes_transferred}");
-        if (current_time - start_time) < 10_00_000 || bytes_transferred == 0 {
-            bytes_sec = 0;
-            formatted_bytes_sec = String::from("-");
+        let current_time = ostree::glib::monotonic_time();
+        let start_time = value::<u64>(p, "start-time") as i64;
+        let bytes_sec = if (current_time - start_time) < 1_000_000 || bytes_transferred == 0 {
+            0
         } else {
-            bytes_sec = (bytes_transferred as f64
-                / ((current_time - start_time) as f64 / 10_00_000f64))
-                as u64;
-            formatted_bytes_sec = format_size(bytes_sec, DECIMAL);
-        }
+            (bytes_transferred as f64 / ((current_time - start_time) as f64 / 1_000_000f64)) as u64
+        };
 
-        if total_delta_parts > 0  {
-            let fetched_delta_part_size = p.variant("fetched-delta-part-size").unwrap().get::<u64>().unwrap();
-            let formatted_fetched: String;
-            let formatted_total: String;
+        let est_time_remaining = match bytes_sec {
+            0 => 0,
+            bytes_sec => total_delta_part_size.saturating_sub(fetched_delta_part_size) / bytes_sec,
+        };
 
-            let fetched_delta_parts = fetched_delta_parts + fetched_delta_part_fallbacks;
-            let total_delta_parts = total_delta_parts + total_delta_parts_fallback;
-
-            // println!("DELTA SIZE: {fetched_delta_part_size}/{total_delta_part_size}");
-
-            // println!("DELAT: {fetched_delta_parts}/{total_delta_parts}");
+        Progress {
+            status: value::<String>(p, "status"),
+            caught_error: value::<bool>(p, "caught-error"),
+            outstanding_fetches: value::<u32>(p, "outstanding-fetches"),
+            outstanding_metadata_fetches: value::<u32>(p, "outstanding-metadata-fetches"),
+            outstanding_writes: value::<u32>(p, "outstanding-writes"),
+            scanning: value::<u32>(p, "scanning"),
+            scanned_metadata: value::<u32>(p, "scanned-metadata"),
+            fetched: value::<u32>(p, "fetched"),
+            metadata_fetched: value::<u32>(p, "metadata-fetched"),
+            requested: value::<u32>(p, "requested"),
+            fetched_delta_parts,
+            total_delta_parts,
+            fetched_delta_part_size,
+            total_delta_part_size,
+            bytes_transferred,
+            bytes_sec,
+            est_time_remaining,
+        }
+    }
+}
 
-            formatted_fetched = format_size(fetched_delta_part_size, DECIMAL);
-            formatted_total = format_size(total_delta_part_size, DECIMAL);
+impl Progress {
+    pub fn message(&self) -> String {
+        if !self.status.is_empty() {
+            return self.status.clone();
+        }
+        if self.caught_error {
+            return String::from("caught error, waiting for outstanding tasks");
+        }
+        if self.outstanding_fetches == 0 {
+            if self.outstanding_writes > 0 {
+                return format!("Writing objects: {}", self.outstanding_writes);
+            }
+            return format!("Scanning metadata: {}", self.scanned_metadata);
+        }
 
-            if bytes_sec > 0 {
-                let est_time_remaining;
-                if total_delta_part_size > fetched_delta_part_size {
-                    est_time_remaining = (total_delta_part_size - fetched_delta_part_size) / bytes_sec;
-                } else {
-                    est_time_remaining = 0;
-                }
+        let formatted_bytes_transferred = format_size(self.bytes_transferred, DECIMAL);
+        let formatted_bytes_sec = match self.bytes_sec {
+            0 => String::from("-"),
+            bytes_sec => format_size(bytes_sec, DECIMAL),
+        };
 
-                let formatted_est_time_remaining = format_duration(Duration::from_secs(est_time_remaining)).to_string();
+        if self.total_delta_parts > 0 {
+            let formatted_fetched = format_size(self.fetched_delta_part_size, DECIMAL);
+            let formatted_total = format_size(self.total_delta_part_size, DECIMAL);
+            let (fetched_delta_parts, total_delta_parts) =
+                (self.fetched_delta_parts, self.total_delta_parts);
 
-                message.push_str(&format!("Receiving delta parts: {fetched_delta_parts}/{total_delta_parts} {formatted_fetched}/{formatted_total}, {formatted_bytes_sec}/s {formatted_est_time_remaining}remaining"));
+            if self.bytes_sec > 0 {
+                let formatted_est_time_remaining =
+                    format_duration(Duration::from_secs(self.est_time_remaining)).to_string();
+                format!("Receiving delta parts: {fetched_delta_parts}/{total_delta_parts} {formatted_fetched}/{formatted_total}, {formatted_bytes_sec}/s {formatted_est_time_remaining}remaining")
             } else {
-                message.push_str(&format!("Receiving delta parts: {fetched_delta_parts}/{total_delta_parts} {formatted_fetched}/{formatted_total}"));
+                format!("Receiving delta parts: {fetched_delta_parts}/{total_delta_parts} {formatted_fetched}/{formatted_total}")
             }
-        } else
-        if scanning > 0 || outstanding_metadata_fetches > 0 {
-            message.push_str(&format!("Receiving metadata objects: {metadata_fetched}/(estimating) {formatted_bytes_sec}/s {formatted_bytes_transferred}"))
+        } else if self.scanning > 0 || self.outstanding_metadata_fetches > 0 {
+            format!("Receiving metadata objects: {}/(estimating) {formatted_bytes_sec}/s {formatted_bytes_transferred}", self.metadata_fetched)
         } else {
-            message.push_str(&format!("Receiving objects: {}% ({fetched}/{requested}) {formatted_bytes_sec}/s {formatted_bytes_transferred}", ((fetched as f32 / requested as f32) * 100.0) as u32));
+            format!("Receiving objects: {}% ({}/{}) {formatted_bytes_sec}/s {formatted_bytes_transferred}", ((self.fetched as f32 / self.requested as f32) * 100.0) as u32, self.fetched, self.requested)
         }
-    } else if outstanding_writes > 0 {
-        message.push_str(&format!("Writing objects: {outstanding_writes}"));
-    } else {
-        message.push_str(&format!("Scanning metadata: {scanned_metadata}"));
     }
+}
+
+pub fn update_callback(p: &AsyncProgress) {
+    let message = Progress::from(p).message();
     print!("\r{}              ", message);
     std::io::stdout().flush().unwrap();
 }
+
+fn value<T: FromVariant + Default>(p: &AsyncProgress, key: &str) -> T {
+    p.variant(key)
+        .and_then(|variant| variant.get::<T>())
+        .unwrap_or_default()
+}
@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use zbus::{dbus_interface, SignalContext};
+
+use crate::engine::ChangelogEntry;
+use crate::progress::Progress;
+
+/// Time a finished job stays exported.
+pub const LINGER: Duration = Duration::from_secs(60);
+
+/// Result of a finished job, reported to clients before `Finished`.
+#[derive(Debug, Clone, Default)]
+pub struct Outcome {
+    pub changed: bool,
+    pub changelog: Vec<ChangelogEntry>,
+    pub download_size: u64,
+    pub disk_size: u64,
+}
+
+pub enum Event {
+    Progress(Progress),
+    Finished(Result<Outcome, crate::Error>),
+}
+
+/// A running operation. Once finished, the object stays on the bus for
+/// `LINGER` with its result in the `Done`, `Success` and `Error`
+/// properties, so clients that subscribe late can still read it.
+#[derive(Debug)]
+pub struct Job {
+    kind: String,
+    done: bool,
+    success: bool,
+    error: String,
+}
+
+impl Job {
+    pub fn new(kind: &str) -> Job {
+        Job {
+            kind: kind.to_string(),
+            done: false,
+            success: false,
+            error: String::new(),
+        }
+    }
+
+    pub fn finish(&mut self, success: bool, error: &str) {
+        self.done = true;
+        self.success = success;
+        self.error = error.to_string();
+    }
+}
+
+#[dbus_interface(name = "dev.rlxos.updates.Job")]
+impl Job {
+    #[dbus_interface(property)]
+    async fn kind(&self) -> String {
+        self.kind.clone()
+    }
+
+    /// Whether the job ended, `Finished` was emitted by then.
+    #[dbus_interface(property)]
+    async fn done(&self) -> bool {
+        self.done
+    }
+
+    #[dbus_interface(property)]
+    async fn success(&self) -> bool {
+        self.success
+    }
+
+    /// Error of a failed job, empty otherwise.
+    #[dbus_interface(property)]
+    async fn error(&self) -> String {
+        self.error.clone()
+    }
+
+    #[dbus_interface(signal)]
+    pub async fn progress(ctxt: &SignalContext<'_>, progress: Progress) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    pub async fn completed(
+        ctxt: &SignalContext<'_>,
+        changed: bool,
+        changelog: &[ChangelogEntry],
+        download_size: u64,
+        disk_size: u64,
+    ) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    pub async fn finished(ctxt: &SignalContext<'_>, success: bool, error: &str)
+        -> zbus::Result<()>;
+}
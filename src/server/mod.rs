@@ -1,13 +1,34 @@
-use std::error::Error as OtherError;
 use std::fmt::Debug;
-use std::path::PathBuf;
-use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
 
 use ostree::gio::Cancellable;
-use tracing::info;
-use zbus::{dbus_interface, DBusError};
+use ostree::prelude::*;
+use ostree::AsyncProgress;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tracing::{info, warn};
+use zbus::fdo::DBusProxy;
+use zbus::names::BusName;
+use zbus::zvariant::{ObjectPath, OwnedObjectPath};
+use zbus::{dbus_interface, Connection, DBusError, MessageHeader, SignalContext};
 
-use crate::engine::Engine;
+use crate::config::Schedule;
+use crate::engine::{local_url, read_journal, Engine, Entry, Initiator, State, Target};
+use crate::progress::Progress;
+
+use self::job::Event;
+pub use self::job::{Job, Outcome};
+pub use self::schedule::{schedule, Report};
+
+mod job;
+mod schedule;
+
+pub const BUS_NAME: &str = "dev.rlxos.updates";
+pub const OBJECT_PATH: &str = "/dev/rlxos/updates";
+
+type DeploymentInfo = ((String, String), Vec<(String, String)>);
+type HistoryEntry = (u64, String, String, u32, bool, String, String, String);
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 #[repr(u8)]
@@ -15,20 +36,196 @@ pub enum Status {
     Idle = 0,
     Checking = 1,
     Deploying = 2,
+    Downloading = 3,
 }
 
 #[derive(Debug)]
 pub struct Server {
-    engine: Mutex<Engine>,
-    status: Status,
+    engine: Arc<Mutex<Engine>>,
+    status: Arc<Mutex<Status>>,
+    cancellable: Arc<Mutex<Option<Cancellable>>>,
+    report: Arc<Mutex<Report>>,
+    jobs: AtomicU32,
+    /// Journal of the engine, read by `history` without taking the engine.
+    journal: PathBuf,
 }
 
 impl Server {
-    pub fn new() -> Result<Server, Error> {
-        Ok(Server {
-            engine: Engine::new(&PathBuf::from("/"))?.into(),
-            status: Status::Idle,
+    pub fn new(engine: Engine) -> Server {
+        Server {
+            report: Arc::new(Mutex::new(Report::load(&engine))),
+            journal: engine.state_path("journal"),
+            engine: Arc::new(Mutex::new(engine)),
+            status: Arc::new(Mutex::new(Status::Idle)),
+            cancellable: Arc::new(Mutex::new(None)),
+            jobs: AtomicU32::new(0),
+        }
+    }
+
+    fn last_check(&self) -> u64 {
+        match self.report.lock() {
+            Ok(report) => report.last_check,
+            Err(_) => 0,
+        }
+    }
+
+    /// Start the job of a scheduled run: check for updates, then pull or
+    /// deploy them depending on `schedule`.
+    async fn scheduled(
+        &self,
+        connection: &Connection,
+        ctxt: &SignalContext<'_>,
+        schedule: Schedule,
+    ) -> Result<OwnedObjectPath, Error> {
+        let (kind, status) = match schedule {
+            Schedule::Off | Schedule::Check => ("check", Status::Checking),
+            Schedule::Download => ("download", Status::Downloading),
+            Schedule::Apply => ("apply", Status::Deploying),
+        };
+        let report = self.report.clone();
+        self.spawn(
+            connection,
+            Initiator::daemon(),
+            ctxt,
+            kind,
+            status,
+            move |engine, progress, cancellable| {
+                let state = engine.state()?;
+                // Downloads and updates report what they transferred, only
+                // checks need the estimate.
+                let estimate = matches!(schedule, Schedule::Off | Schedule::Check);
+                let (changed, changelog, estimate) =
+                    engine.check(&state, None, estimate, Some(progress), Some(cancellable))?;
+                let estimate = estimate.unwrap_or_default();
+                schedule::checked(&report, engine, changed);
+                if changed {
+                    match schedule {
+                        Schedule::Download => {
+                            engine.download(&state, None, Some(progress), Some(cancellable))?;
+                        }
+                        Schedule::Apply => {
+                            engine.apply(&state, None, Some(progress), Some(cancellable))?;
+                            set_available(&report, false);
+                        }
+                        Schedule::Off | Schedule::Check => {}
+                    }
+                }
+                Ok(Outcome {
+                    changed,
+                    changelog: changelog.entries,
+                    download_size: estimate.download_size,
+                    disk_size: estimate.disk_size,
+                })
+            },
+        )
+        .await
+    }
+
+    /// Refresh the pending deployment once no job holds the engine.
+    async fn refresh(&self) {
+        let report = self.report.clone();
+        let _ = self
+            .blocking(move |engine| {
+                if let Ok(mut report) = report.lock() {
+                    report.pending = engine.pending().unwrap_or_default();
+                }
+                Ok(())
+            })
+            .await;
+    }
+
+    /// Run `operation` on a blocking thread, so sysroot and network I/O
+    /// don't stall the other calls on the bus. Fails while a job holds the
+    /// engine.
+    async fn blocking<F, T>(&self, operation: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Engine) -> Result<T, crate::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let engine = self.engine.clone();
+        tokio::task::spawn_blocking(move || match engine.try_lock() {
+            Ok(engine) => operation(&engine).map_err(Error::from),
+            Err(_) => Err(Error::EngineIsBusy),
         })
+        .await
+        .map_err(|error| Error::Engine(format!("ERROR: {error}")))?
+    }
+
+    /// Export a new job object and run `operation` on a blocking thread,
+    /// forwarding its progress and result as signals on the job object.
+    async fn spawn<F>(
+        &self,
+        connection: &Connection,
+        initiator: Initiator,
+        ctxt: &SignalContext<'_>,
+        kind: &str,
+        status: Status,
+        operation: F,
+    ) -> Result<OwnedObjectPath, Error>
+    where
+        F: FnOnce(&Engine, &AsyncProgress, &Cancellable) -> Result<Outcome, crate::Error>
+            + Send
+            + 'static,
+    {
+        let id = self.jobs.fetch_add(1, Ordering::SeqCst);
+        let path: OwnedObjectPath = ObjectPath::try_from(format!("{OBJECT_PATH}/jobs/{id}"))
+            .map_err(zbus::Error::from)?
+            .into();
+
+        {
+            let mut current = self.status.lock().map_err(|_| Error::EngineIsBusy)?;
+            if *current != Status::Idle {
+                return Err(Error::EngineIsBusy);
+            }
+            *current = status;
+        }
+
+        if let Err(error) = connection.object_server().at(&path, Job::new(kind)).await {
+            set_status(&self.status, Status::Idle);
+            return Err(error.into());
+        }
+        if let Err(error) = self.status_changed(ctxt).await {
+            let _ = connection.object_server().remove::<Job, _>(&path).await;
+            set_status(&self.status, Status::Idle);
+            return Err(error.into());
+        }
+        info!("Started {} job at {}", kind, path.as_str());
+
+        let cancellable = Cancellable::new();
+        set_cancellable(&self.cancellable, Some(cancellable.clone()));
+
+        let (sender, receiver) = mpsc::unbounded_channel::<Event>();
+        let engine = self.engine.clone();
+        let kind = kind.to_string();
+        tokio::task::spawn_blocking(move || {
+            let progress = AsyncProgress::new();
+            let progress_sender = sender.clone();
+            progress.connect_changed(move |p| {
+                let _ = progress_sender.send(Event::Progress(Progress::from(p)));
+            });
+
+            let result = match engine.lock() {
+                Ok(engine) => engine.lock().and_then(|_| {
+                    let result = engine.record(&kind, initiator, || {
+                        operation(&engine, &progress, &cancellable)
+                    });
+                    engine.unlock();
+                    result
+                }),
+                Err(_) => Err(crate::Error::EngineIsBusy),
+            };
+            let _ = sender.send(Event::Finished(result));
+        });
+
+        tokio::spawn(watch(
+            connection.clone(),
+            path.clone(),
+            self.status.clone(),
+            self.cancellable.clone(),
+            receiver,
+        ));
+
+        Ok(path)
     }
 }
 
@@ -36,150 +233,477 @@ impl Server {
 impl Server {
     #[dbus_interface(property)]
     async fn status(&self) -> u8 {
-        self.status as u8
-    }
-
-    async fn check(&mut self) -> Result<(bool, String), Error> {
-        if self.status != Status::Idle {
-            return Err(Error::EngineIsBusy);
+        match self.status.lock() {
+            Ok(status) => *status as u8,
+            Err(_) => Status::Idle as u8,
         }
+    }
 
-        if let Ok(engine) = self.engine.lock() {
-            engine.lock()?;
-
-            self.status = Status::Checking;
-            let result = engine.check(None, Cancellable::NONE);
-            engine.unlock();
-            self.status = Status::Idle;
-            let (changed, changelog) = result?;
+    /// Unix time of the last successful check, 0 when there was none.
+    #[dbus_interface(property)]
+    async fn last_check_time(&self) -> u64 {
+        self.last_check()
+    }
 
-            Ok((changed, changelog))
-        } else {
-            Err(Error::EngineIsBusy)
+    /// Whether the last check found an update that isn't deployed yet.
+    #[dbus_interface(property)]
+    async fn update_available(&self) -> bool {
+        match self.report.lock() {
+            Ok(report) => report.update_available,
+            Err(_) => false,
         }
     }
 
-    async fn apply(&mut self) -> Result<bool, Error> {
-        if self.status != Status::Idle {
-            return Err(Error::EngineIsBusy);
+    /// Revision of the deployment that boots next, empty when none.
+    #[dbus_interface(property)]
+    async fn pending_deployment(&self) -> String {
+        match self.report.lock() {
+            Ok(report) => report.pending.clone(),
+            Err(_) => String::new(),
         }
+    }
 
-        if let Ok(engine) = self.engine.lock() {
-            engine.lock()?;
-
-            self.status = Status::Deploying;
-            let result = engine.apply(None, Cancellable::NONE);
-            self.status = Status::Idle;
-
-            engine.unlock();
+    async fn check(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> Result<OwnedObjectPath, Error> {
+        let report = self.report.clone();
+        self.spawn(
+            connection,
+            caller(connection, &header).await,
+            &ctxt,
+            "check",
+            Status::Checking,
+            move |engine, progress, cancellable| {
+                let state = engine.state()?;
+                let (changed, changelog, estimate) =
+                    engine.check(&state, None, true, Some(progress), Some(cancellable))?;
+                let estimate = estimate.unwrap_or_default();
+                schedule::checked(&report, engine, changed);
+                Ok(Outcome {
+                    changed,
+                    changelog: changelog.entries,
+                    download_size: estimate.download_size,
+                    disk_size: estimate.disk_size,
+                })
+            },
+        )
+        .await
+    }
 
-            let changed = result?;
+    async fn apply(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> Result<OwnedObjectPath, Error> {
+        let report = self.report.clone();
+        self.spawn(
+            connection,
+            caller(connection, &header).await,
+            &ctxt,
+            "apply",
+            Status::Deploying,
+            move |engine, progress, cancellable| {
+                let state = engine.state()?;
+                let changed = engine.apply(&state, None, Some(progress), Some(cancellable))?;
+                set_available(&report, false);
+                Ok(Outcome {
+                    changed,
+                    ..Default::default()
+                })
+            },
+        )
+        .await
+    }
 
-            Ok(changed)
-        } else {
-            Err(Error::EngineIsBusy)
-        }
+    /// Apply updates pulled from the local repository at `path`, verified
+    /// with the keys of the configured remote.
+    async fn apply_from(
+        &self,
+        path: String,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> Result<OwnedObjectPath, Error> {
+        let url = local_url(Path::new(&path))?;
+        let report = self.report.clone();
+        self.spawn(
+            connection,
+            caller(connection, &header).await,
+            &ctxt,
+            "apply",
+            Status::Deploying,
+            move |engine, progress, cancellable| {
+                let state = engine.state()?;
+                let changed =
+                    engine.apply(&state, Some(&url), Some(progress), Some(cancellable))?;
+                set_available(&report, false);
+                Ok(Outcome {
+                    changed,
+                    ..Default::default()
+                })
+            },
+        )
+        .await
     }
 
-    async fn state(&mut self) -> Result<Vec<((String, String), Vec<(String, String)>)>, Error> {
-        if let Ok(engine) = self.engine.lock() {
-            let mut result: Vec<((String, String), Vec<(String, String)>)> = Vec::new();
-            for state in engine.states()? {
-                let mut extensions_list: Vec<(String, String)> = Vec::new();
-                for extension in state.extensions {
-                    extensions_list.push((extension.refspec.clone(), extension.revision.clone()));
-                }
+    /// Pull available updates without deploying them, a later `Apply`
+    /// deploys them offline.
+    async fn download(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> Result<OwnedObjectPath, Error> {
+        self.spawn(
+            connection,
+            caller(connection, &header).await,
+            &ctxt,
+            "download",
+            Status::Downloading,
+            |engine, progress, cancellable| {
+                let state = engine.state()?;
+                let (changed, changelog, transferred) =
+                    engine.download(&state, None, Some(progress), Some(cancellable))?;
+                Ok(Outcome {
+                    changed,
+                    changelog: changelog.entries,
+                    download_size: transferred,
+                    ..Default::default()
+                })
+            },
+        )
+        .await
+    }
 
-                result.push((
-                    (state.core.refspec.clone(), state.core.revision.clone()),
-                    extensions_list,
-                ));
+    /// Cancel the running job, returns false when there is nothing to cancel.
+    async fn cancel(&self) -> bool {
+        match self.cancellable.lock().as_deref() {
+            Ok(Some(cancellable)) => {
+                info!("Cancelling running job");
+                cancellable.cancel();
+                true
             }
-            Ok(result)
-        } else {
-            Err(Error::EngineIsBusy)
+            _ => false,
         }
     }
 
-    async fn switch(&mut self, channel: &str) -> Result<bool, Error> {
-        if self.status != Status::Idle {
-            return Err(Error::EngineIsBusy);
-        }
-
-        if let Ok(engine) = self.engine.lock() {
-            engine.lock()?;
+    async fn state(&self) -> Result<Vec<DeploymentInfo>, Error> {
+        let states = self.blocking(|engine| engine.states()).await?;
+        let mut result: Vec<DeploymentInfo> = Vec::new();
+        for state in states {
+            let mut extensions_list: Vec<(String, String)> = Vec::new();
+            for extension in state.extensions {
+                extensions_list.push((extension.refspec.clone(), extension.revision.clone()));
+            }
 
-            self.status = Status::Deploying;
-            let result = engine.switch(channel, None, Cancellable::NONE);
-            self.status = Status::Idle;
+            result.push((
+                (state.core.refspec.clone(), state.core.revision.clone()),
+                extensions_list,
+            ));
+        }
+        Ok(result)
+    }
 
-            engine.unlock();
+    async fn switch(
+        &self,
+        channel: String,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> Result<OwnedObjectPath, Error> {
+        self.spawn(
+            connection,
+            caller(connection, &header).await,
+            &ctxt,
+            "switch",
+            Status::Deploying,
+            move |engine, progress, cancellable| {
+                let changed = engine.switch(&channel, None, Some(progress), Some(cancellable))?;
+                Ok(Outcome {
+                    changed,
+                    ..Default::default()
+                })
+            },
+        )
+        .await
+    }
 
-            let changed = result?;
+    async fn reset(
+        &self,
+        channel: String,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> Result<OwnedObjectPath, Error> {
+        self.spawn(
+            connection,
+            caller(connection, &header).await,
+            &ctxt,
+            "reset",
+            Status::Deploying,
+            move |engine, progress, cancellable| {
+                let changed = engine.reset(&channel, None, Some(progress), Some(cancellable))?;
+                Ok(Outcome {
+                    changed,
+                    ..Default::default()
+                })
+            },
+        )
+        .await
+    }
 
-            Ok(changed)
-        } else {
-            Err(Error::EngineIsBusy)
-        }
+    async fn add_extension(
+        &self,
+        extensions: Vec<String>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> Result<OwnedObjectPath, Error> {
+        info!("Adding extensions: {:?}", extensions);
+        self.spawn(
+            connection,
+            caller(connection, &header).await,
+            &ctxt,
+            "add-extension",
+            Status::Deploying,
+            move |engine, progress, cancellable| {
+                let changed =
+                    engine.add_extension(extensions, None, Some(progress), Some(cancellable))?;
+                Ok(Outcome {
+                    changed,
+                    ..Default::default()
+                })
+            },
+        )
+        .await
     }
 
-    async fn reset(&mut self, channel: &str) -> Result<bool, Error> {
-        if self.status != Status::Idle {
-            return Err(Error::EngineIsBusy);
-        }
+    /// Make a previous deployment the default boot entry. An empty `revision`
+    /// and a negative `index` select the default rollback target.
+    async fn rollback(
+        &self,
+        index: i32,
+        revision: String,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> Result<OwnedObjectPath, Error> {
+        let target = match (index, revision) {
+            (_, revision) if !revision.is_empty() => Target::Revision(revision),
+            (index, _) if index >= 0 => Target::Index(index as usize),
+            _ => Target::Rollback,
+        };
+
+        self.spawn(
+            connection,
+            caller(connection, &header).await,
+            &ctxt,
+            "rollback",
+            Status::Deploying,
+            move |engine, _, cancellable| {
+                let changed = engine.rollback(&target, Some(cancellable))?;
+                Ok(Outcome {
+                    changed,
+                    ..Default::default()
+                })
+            },
+        )
+        .await
+    }
 
-        if let Ok(engine) = self.engine.lock() {
+    /// Pin or unpin the deployment at `index` so it survives pruning.
+    async fn pin(&self, index: u32, pinned: bool) -> Result<bool, Error> {
+        self.blocking(move |engine| {
             engine.lock()?;
+            let result = engine.pin(&Target::Index(index as usize), pinned);
+            engine.unlock();
+            result
+        })
+        .await
+    }
 
-            self.status = Status::Deploying;
-            let result = engine.reset(channel, None, Cancellable::NONE);
-            self.status = Status::Idle;
+    /// Drop the staged deployment, returns false when nothing is staged.
+    async fn unstage(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> Result<bool, Error> {
+        let initiator = caller(connection, &header).await;
+        let unstaged = self
+            .blocking(move |engine| {
+                engine.lock()?;
+                let result =
+                    engine.record("unstage", initiator, || engine.unstage(Cancellable::NONE));
+                engine.unlock();
+                result
+            })
+            .await?;
+
+        self.refresh().await;
+        self.pending_deployment_changed(&ctxt).await?;
+        Ok(unstaged)
+    }
 
-            engine.unlock();
+    /// Recorded operations, newest first, as (timestamp, operation,
+    /// initiator, uid, success, error, old revision, new revision).
+    async fn history(&self) -> Result<Vec<HistoryEntry>, Error> {
+        // Read without the engine lock, so running jobs don't hide history.
+        let path = self.journal.clone();
+        let entries = tokio::task::spawn_blocking(move || read_journal::<Entry>(&path))
+            .await
+            .map_err(|error| Error::Engine(format!("ERROR: {error}")))??;
+        let revision = |state: &Option<State>| match state {
+            Some(state) => state.revision.clone(),
+            None => String::new(),
+        };
+        Ok(entries
+            .into_iter()
+            .map(|entry| {
+                (
+                    entry.timestamp,
+                    entry.operation.clone(),
+                    entry.initiator.name().to_string(),
+                    entry.initiator.uid(),
+                    entry.success,
+                    entry.errors.join(": "),
+                    revision(&entry.old),
+                    revision(&entry.new),
+                )
+            })
+            .collect())
+    }
 
-            let changed = result?;
+    async fn list(&self) -> Result<Vec<String>, Error> {
+        self.blocking(|engine| engine.list(None, Cancellable::NONE)).await
+    }
+}
 
-            Ok(changed)
-        } else {
-            Err(Error::EngineIsBusy)
+/// Forward job events as signals until the job finishes, then release the
+/// server and drop the job object from the bus once it lingered.
+async fn watch(
+    connection: Connection,
+    path: OwnedObjectPath,
+    status: Arc<Mutex<Status>>,
+    cancellable: Arc<Mutex<Option<Cancellable>>>,
+    mut receiver: UnboundedReceiver<Event>,
+) {
+    let ctxt = SignalContext::new(&connection, path.as_ref()).unwrap();
+    let mut finished = None;
+    while let Some(event) = receiver.recv().await {
+        let emitted = match event {
+            Event::Progress(progress) => Job::progress(&ctxt, progress).await,
+            Event::Finished(Ok(outcome)) => {
+                finished = Some((true, String::new()));
+                match Job::completed(
+                    &ctxt,
+                    outcome.changed,
+                    &outcome.changelog,
+                    outcome.download_size,
+                    outcome.disk_size,
+                )
+                .await
+                {
+                    Ok(_) => Job::finished(&ctxt, true, "").await,
+                    Err(error) => Err(error),
+                }
+            }
+            Event::Finished(Err(error)) => {
+                let error = get_error_str(error);
+                let emitted = Job::finished(&ctxt, false, &error).await;
+                finished = Some((false, error));
+                emitted
+            }
+        };
+        if let Err(error) = emitted {
+            warn!("failed to emit signal for {}: {}", path.as_str(), error);
+        }
+        if finished.is_some() {
+            break;
         }
     }
 
-    async fn add_extension(&mut self, extensions: Vec<String>) -> Result<bool, Error> {
-        if self.status != Status::Idle {
-            return Err(Error::EngineIsBusy);
+    let (success, error) = match finished {
+        Some(finished) => finished,
+        None => {
+            let error = String::from("ERROR: job aborted");
+            let _ = Job::finished(&ctxt, false, &error).await;
+            (false, error)
         }
+    };
+
+    let object_server = connection.object_server();
+    if let Ok(iface) = object_server.interface::<_, Job>(&path).await {
+        iface.get_mut().await.finish(success, &error);
+        let job = iface.get().await;
+        let ctxt = iface.signal_context();
+        let _ = job.done_changed(ctxt).await;
+        let _ = job.success_changed(ctxt).await;
+        let _ = job.error_changed(ctxt).await;
+    }
 
-        if let Ok(engine) = self.engine.lock() {
-            engine.lock()?;
-            info!("Adding extensions: {:?}", extensions);
+    set_cancellable(&cancellable, None);
+    set_status(&status, Status::Idle);
+    if let Ok(iface) = object_server.interface::<_, Server>(OBJECT_PATH).await {
+        let server = iface.get().await;
+        server.refresh().await;
+        let ctxt = iface.signal_context();
+        let _ = server.status_changed(ctxt).await;
+        let _ = server.last_check_time_changed(ctxt).await;
+        let _ = server.update_available_changed(ctxt).await;
+        let _ = server.pending_deployment_changed(ctxt).await;
+    }
 
-            self.status = Status::Deploying;
-            let result = engine.add_extension(extensions, None, Cancellable::NONE);
-            self.status = Status::Idle;
+    tokio::time::sleep(job::LINGER).await;
+    let _ = object_server.remove::<Job, _>(&path).await;
+}
 
-            engine.unlock();
+/// Initiator of a method call, from the uid the bus reports for its sender.
+async fn caller(connection: &Connection, header: &MessageHeader<'_>) -> Initiator {
+    let uid = match header.sender() {
+        Ok(Some(sender)) => match DBusProxy::new(connection).await {
+            Ok(proxy) => proxy
+                .get_connection_unix_user(BusName::from(sender.to_owned()))
+                .await
+                .ok(),
+            Err(_) => None,
+        },
+        _ => None,
+    };
+    if uid.is_none() {
+        warn!("failed to resolve uid of caller");
+    }
+    Initiator::DBus {
+        uid: uid.unwrap_or(u32::MAX),
+    }
+}
 
-            let changed = result?;
+fn set_cancellable(cancellable: &Mutex<Option<Cancellable>>, value: Option<Cancellable>) {
+    if let Ok(mut cancellable) = cancellable.lock() {
+        *cancellable = value;
+    }
+}
 
-            Ok(changed)
-        } else {
-            Err(Error::EngineIsBusy)
-        }
+fn set_available(report: &Mutex<Report>, available: bool) {
+    if let Ok(mut report) = report.lock() {
+        report.update_available = available;
     }
+}
 
-    async fn list(&mut self) -> Result<Vec<String>, Error> {
-        if let Ok(engine) = self.engine.lock() {
-            let list = engine.list(None, Cancellable::NONE)?;
-            Ok(list)
-        } else {
-            Err(Error::EngineIsBusy)
-        }
+fn set_status(status: &Mutex<Status>, value: Status) {
+    if let Ok(mut status) = status.lock() {
+        *status = value;
     }
 }
 
 #[derive(Debug, DBusError)]
 pub enum Error {
+    #[dbus_error(zbus_error)]
+    ZBus(zbus::Error),
     Engine(String),
     EngineIsBusy,
 }
@@ -191,17 +715,6 @@ impl From<crate::Error> for Error {
 }
 
 fn get_error_str(error: crate::Error) -> String {
-    let sources = sources(&error);
-    let error = sources.join(": ");
+    let error = error.chain().join(": ");
     format!("ERROR: {error}")
 }
-
-fn sources(error: &crate::Error) -> Vec<String> {
-    let mut sources = vec![error.to_string()];
-    let mut source = error.source();
-    while let Some(error) = source.take() {
-        sources.push(error.to_string());
-        source = error.source();
-    }
-    sources
-}
@@ -0,0 +1,131 @@
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::{info, warn};
+use zbus::Connection;
+
+use crate::config::{Config, Schedule};
+use crate::engine::Engine;
+
+use super::{Error, Server, OBJECT_PATH};
+
+/// Delay before retrying a run the server was too busy for.
+const BUSY_RETRY: Duration = Duration::from_secs(5 * 60);
+
+/// Results of the latest check and deploy, exposed as server properties.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// Unix time of the last successful check, 0 when there was none.
+    pub last_check: u64,
+    pub update_available: bool,
+    /// Revision of the deployment that boots next, empty when none.
+    pub pending: String,
+}
+
+impl Report {
+    /// Report with the last check time persisted in the state directory.
+    pub fn load(engine: &Engine) -> Report {
+        let last_check = fs::read_to_string(engine.state_path("last-check"))
+            .ok()
+            .and_then(|time| time.trim().parse().ok())
+            .unwrap_or(0);
+        Report {
+            last_check,
+            update_available: false,
+            pending: engine.pending().unwrap_or_default(),
+        }
+    }
+}
+
+/// Record a finished check and persist its time.
+pub fn checked(report: &Mutex<Report>, engine: &Engine, available: bool) {
+    let now = now();
+    if let Ok(mut report) = report.lock() {
+        report.last_check = now;
+        report.update_available = available;
+    }
+
+    let path = engine.state_path("last-check");
+    let written = match path.parent() {
+        Some(parent) => fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+    .and_then(|_| fs::write(&path, format!("{}\n", now)));
+    if let Err(error) = written {
+        warn!("failed to write {:?}: {}", path, error);
+    }
+}
+
+/// Run the configured schedule on the server exported on `connection`:
+/// every `interval` after the last check, plus a random delay, start a
+/// scheduled job unless another one is running.
+pub async fn schedule(connection: Connection, config: Config) {
+    if config.schedule == Schedule::Off {
+        return;
+    }
+    info!(
+        "scheduling {:?} every {}s",
+        config.schedule,
+        config.interval.as_secs()
+    );
+
+    let mut last_run = 0;
+    loop {
+        let Ok(iface) = connection
+            .object_server()
+            .interface::<_, Server>(OBJECT_PATH)
+            .await
+        else {
+            warn!("server is not exported, stopping schedule");
+            return;
+        };
+
+        let last_check = iface.get().await.last_check();
+        let due = last_run.max(last_check) + config.interval.as_secs();
+        let now = now();
+        if due > now {
+            tokio::time::sleep(Duration::from_secs(due - now)).await;
+            continue;
+        }
+        tokio::time::sleep(jitter(config.random_delay)).await;
+
+        let started = iface
+            .get()
+            .await
+            .scheduled(&connection, iface.signal_context(), config.schedule)
+            .await;
+        match started {
+            Ok(path) => {
+                info!("started scheduled job at {}", path.as_str());
+                last_run = self::now();
+            }
+            Err(Error::EngineIsBusy) => {
+                info!("engine is busy, retrying scheduled job later");
+                tokio::time::sleep(BUSY_RETRY).await;
+            }
+            Err(error) => {
+                warn!("failed to start scheduled job: {:?}", error);
+                last_run = self::now();
+            }
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Delay up to `max`, spreading scheduled runs of many machines.
+fn jitter(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.subsec_nanos() as u64)
+        ^ std::process::id() as u64;
+    Duration::from_secs(seed % max.as_secs().max(1))
+}